@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+
+use toml;
+
+const SOCKET: &'static str = "127.0.0.1:3000";
+const UPLOAD_DIR: &'static str = "./uploads";
+const METADATA_DIR: &'static str = "./metadata";
+const STORAGE: &'static str = "filesystem";
+const DATABASE: &'static str = "./pastes.sqlite";
+const ID_LEN: usize = 5;
+const ID_ALPHABET: &'static str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const KEY_BYTES: usize = 8;
+const MAX_PASTE_BYTES: usize = 2 * 1024 * 1024; // 2 MB
+const PASTE_DAYS: u32 = 30; // u32 needed for Duration checked_mul()
+const HIGHLIGHT_MAX_LINES: usize = 20000;
+const HIGHLIGHT_MAX_LINE_LEN: usize = 10000;
+const HIGHLIGHT_THREADS: usize = 4;
+const VERSIONS: usize = 5;
+const MAX_RENEWALS: u32 = 12;
+const CLEANUP_INTERVAL_SECS: u64 = 60 * 60;
+const TOMBSTONE_DAYS: u32 = 7;
+const TRASH_DAYS: u32 = 0;
+const BATCH_MAX_BYTES: usize = 16 * 1024 * 1024; // 16 MB
+const DISK_QUOTA_BYTES: usize = 1024 * 1024 * 1024; // 1 GB
+const RATE_LIMIT: u32 = 30;
+const RATE_WINDOW_SECS: u64 = 60;
+const THEMES_DIR: &'static str = "./themes";
+const SYNTAXES_DIR: &'static str = "./syntaxes";
+const PNG_FONT: &'static str = "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf";
+const EXAMPLE_ID: &'static str = "vxcRz";
+const EXAMPLE_KEY: &'static str = "a7772362cf6e2c36";
+const EXAMPLE_EXT: &'static str = "rs";
+
+pub const DAY_SECS: i64 = 60 * 60 * 24;
+// how the hashes printed by --hash-password start, see credential_hash()
+pub const PASSWORD_HASH_SCHEME: &'static str = "pbkdf2-sha256$";
+
+// Everything is optional in the file itself; missing values take the defaults above.
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    socket: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    upload_dir: Option<String>,
+    metadata_dir: Option<String>,
+    storage: Option<String>,
+    database: Option<String>,
+    id_len: Option<usize>,
+    id_alphabet: Option<String>,
+    key_bytes: Option<usize>,
+    max_paste_bytes: Option<usize>,
+    batch_max_bytes: Option<usize>,
+    paste_days: Option<u32>,
+    tombstone_days: Option<u32>,
+    trash_days: Option<u32>,
+    cleanup_interval_secs: Option<u64>,
+    disk_quota_bytes: Option<usize>,
+    rate_limit: Option<u32>,
+    rate_window_secs: Option<u64>,
+    max_pastes_per_ip: Option<u32>,
+    metrics: Option<bool>,
+    log_format: Option<String>,
+    cors_origins: Option<Vec<String>>,
+    png_font: Option<String>,
+    banner: Option<String>,
+    example_id: Option<String>,
+    example_key: Option<String>,
+    example_ext: Option<String>,
+    themes_dir: Option<String>,
+    syntaxes_dir: Option<String>,
+    dedup: Option<bool>,
+    random_keys: Option<bool>,
+    auth_user: Option<String>,
+    auth_password_hash: Option<String>,
+    admin_password_hash: Option<String>,
+    versions: Option<usize>,
+    max_renewals: Option<u32>,
+    highlight_max_lines: Option<usize>,
+    highlight_max_line_len: Option<usize>,
+    highlight_threads: Option<usize>,
+    language_themes: Option<HashMap<String, String>>
+}
+
+pub struct Config {
+    pub socket: String,
+    pub tls_cert: Option<String>, // PEM certificate chain; with tls_key, the server speaks HTTPS itself
+    pub tls_key: Option<String>, // PEM private key
+    pub upload_dir: String,
+    pub metadata_dir: String,
+    pub storage: String, // "filesystem" or "sqlite"
+    pub database: String, // only used by the sqlite backend
+    pub id_len: usize,
+    pub id_alphabet: String, // the characters randomly generated paste IDs are made of
+    pub key_bytes: usize,
+    pub max_paste_bytes: usize,
+    pub batch_max_bytes: usize, // for all the pastes in a POST /batch together
+    pub paste_days: u32,
+    pub tombstone_days: u32, // how long requests for deleted pastes get 410 Gone rather than 404
+    pub trash_days: u32, // how long deleted pastes may be restored for; 0 deletes them straight away
+    pub cleanup_interval_secs: u64, // how often expired pastes are looked for
+    pub disk_quota_bytes: usize, // 0 disables the quota
+    pub rate_limit: u32, // 0 disables rate limiting
+    pub rate_window_secs: u64,
+    pub max_pastes_per_ip: u32, // live pastes each IP address may have at once; 0 for no limit
+    pub metrics: bool, // whether /metrics is served at all
+    pub log_format: String, // "plain" or "json"
+    pub cors_origins: Vec<String>, // origins allowed to use the API from browsers; "*" for any
+    pub png_font: String, // a monospaced TrueType font for rendering pastes as images
+    pub banner: Option<String>, // shown at the top of / and /help, e.g. contact details or terms
+    pub example_id: String, // the paste used in the examples on / and /help
+    pub example_key: String,
+    pub example_ext: String,
+    pub themes_dir: String, // .tmTheme files in here are offered alongside the built-in themes
+    pub syntaxes_dir: String, // likewise .sublime-syntax files and the built-in languages
+    pub dedup: bool, // whether identical pastes share a single stored copy
+    pub random_keys: bool, // edit keys are random, rather than derived from the ID and HMAC key
+    pub auth_user: Option<String>, // if set, every request needs this user's basic auth credentials
+    pub auth_password_hash: Option<String>, // as printed by --hash-password
+    pub admin_password_hash: Option<String>, // likewise, for the user "admin"; if unset, /admin/* doesn't exist
+    pub versions: usize, // how many previous versions of each paste to keep when replacing it
+    pub max_renewals: u32, // how many times each paste's lifetime may be extended; 0 disables renewing
+    pub highlight_max_lines: usize, // longer pastes are served unhighlighted; 0 for no limit
+    pub highlight_max_line_len: usize, // likewise pastes with longer lines; 0 for no limit
+    pub highlight_threads: usize, // how many pastes may be highlighted at once
+    pub language_themes: HashMap<String, String> // the theme for each extension, when none is asked for
+}
+
+// Written out rather than derived, so that printing the configuration at
+// startup doesn't log the password hashes.
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("socket", &self.socket)
+            .field("tls_cert", &self.tls_cert)
+            .field("tls_key", &self.tls_key)
+            .field("upload_dir", &self.upload_dir)
+            .field("metadata_dir", &self.metadata_dir)
+            .field("storage", &self.storage)
+            .field("database", &self.database)
+            .field("id_len", &self.id_len)
+            .field("id_alphabet", &self.id_alphabet)
+            .field("key_bytes", &self.key_bytes)
+            .field("max_paste_bytes", &self.max_paste_bytes)
+            .field("batch_max_bytes", &self.batch_max_bytes)
+            .field("paste_days", &self.paste_days)
+            .field("tombstone_days", &self.tombstone_days)
+            .field("trash_days", &self.trash_days)
+            .field("cleanup_interval_secs", &self.cleanup_interval_secs)
+            .field("disk_quota_bytes", &self.disk_quota_bytes)
+            .field("rate_limit", &self.rate_limit)
+            .field("rate_window_secs", &self.rate_window_secs)
+            .field("max_pastes_per_ip", &self.max_pastes_per_ip)
+            .field("metrics", &self.metrics)
+            .field("log_format", &self.log_format)
+            .field("cors_origins", &self.cors_origins)
+            .field("png_font", &self.png_font)
+            .field("banner", &self.banner)
+            .field("example_id", &self.example_id)
+            .field("example_key", &self.example_key)
+            .field("example_ext", &self.example_ext)
+            .field("themes_dir", &self.themes_dir)
+            .field("syntaxes_dir", &self.syntaxes_dir)
+            .field("dedup", &self.dedup)
+            .field("random_keys", &self.random_keys)
+            .field("auth_user", &self.auth_user)
+            .field("auth_password_hash", &self.auth_password_hash.as_ref().map(|_| "<redacted>"))
+            .field("admin_password_hash", &self.admin_password_hash.as_ref().map(|_| "<redacted>"))
+            .field("versions", &self.versions)
+            .field("max_renewals", &self.max_renewals)
+            .field("highlight_max_lines", &self.highlight_max_lines)
+            .field("highlight_max_line_len", &self.highlight_max_line_len)
+            .field("highlight_threads", &self.highlight_threads)
+            .field("language_themes", &self.language_themes)
+            .finish()
+    }
+}
+
+impl Config {
+    // The socket and upload directory may also be overridden from the
+    // environment, and the storage backend with a --storage=<backend> flag,
+    // both of which take precedence over the file.
+    pub fn load(path: &str) -> Result<Config, String> {
+        let file = if Path::new(path).exists() {
+            let mut contents = String::new();
+            let mut f = try!(File::open(path).map_err(|e| format!("opening {}: {}", path, e)));
+            try!(f.read_to_string(&mut contents).map_err(|e| format!("reading {}: {}", path, e)));
+            try!(toml::from_str::<ConfigFile>(&contents).map_err(|e| format!("parsing {}: {}", path, e)))
+        } else {
+            ConfigFile::default()
+        };
+
+        let config = Config {
+            socket: env::var("IRON_PASTEBIN_SOCKET").ok()
+                .or(file.socket)
+                .unwrap_or_else(|| SOCKET.to_string()),
+            tls_cert: file.tls_cert,
+            tls_key: file.tls_key,
+            upload_dir: env::var("IRON_PASTEBIN_UPLOAD_DIR").ok()
+                .or(file.upload_dir)
+                .unwrap_or_else(|| UPLOAD_DIR.to_string()),
+            metadata_dir: file.metadata_dir.unwrap_or_else(|| METADATA_DIR.to_string()),
+            storage: env::args().skip(1)
+                .filter_map(|arg| if arg.starts_with("--storage=") { Some(arg["--storage=".len()..].to_string()) } else { None })
+                .last()
+                .or(file.storage)
+                .unwrap_or_else(|| STORAGE.to_string()),
+            database: file.database.unwrap_or_else(|| DATABASE.to_string()),
+            id_len: file.id_len.unwrap_or(ID_LEN),
+            id_alphabet: file.id_alphabet.unwrap_or_else(|| ID_ALPHABET.to_string()),
+            key_bytes: file.key_bytes.unwrap_or(KEY_BYTES),
+            max_paste_bytes: file.max_paste_bytes.unwrap_or(MAX_PASTE_BYTES),
+            batch_max_bytes: file.batch_max_bytes.unwrap_or(BATCH_MAX_BYTES),
+            paste_days: file.paste_days.unwrap_or(PASTE_DAYS),
+            tombstone_days: file.tombstone_days.unwrap_or(TOMBSTONE_DAYS),
+            trash_days: file.trash_days.unwrap_or(TRASH_DAYS),
+            cleanup_interval_secs: file.cleanup_interval_secs.unwrap_or(CLEANUP_INTERVAL_SECS),
+            disk_quota_bytes: file.disk_quota_bytes.unwrap_or(DISK_QUOTA_BYTES),
+            rate_limit: file.rate_limit.unwrap_or(RATE_LIMIT),
+            rate_window_secs: file.rate_window_secs.unwrap_or(RATE_WINDOW_SECS),
+            max_pastes_per_ip: file.max_pastes_per_ip.unwrap_or(0),
+            metrics: file.metrics.unwrap_or(false),
+            log_format: file.log_format.unwrap_or_else(|| "plain".to_string()),
+            cors_origins: file.cors_origins.unwrap_or_else(Vec::new),
+            png_font: file.png_font.unwrap_or_else(|| PNG_FONT.to_string()),
+            banner: file.banner,
+            example_id: file.example_id.unwrap_or_else(|| EXAMPLE_ID.to_string()),
+            example_key: file.example_key.unwrap_or_else(|| EXAMPLE_KEY.to_string()),
+            example_ext: file.example_ext.unwrap_or_else(|| EXAMPLE_EXT.to_string()),
+            themes_dir: file.themes_dir.unwrap_or_else(|| THEMES_DIR.to_string()),
+            syntaxes_dir: file.syntaxes_dir.unwrap_or_else(|| SYNTAXES_DIR.to_string()),
+            dedup: file.dedup.unwrap_or(false),
+            random_keys: file.random_keys.unwrap_or(false),
+            auth_user: file.auth_user,
+            auth_password_hash: file.auth_password_hash,
+            admin_password_hash: file.admin_password_hash,
+            versions: file.versions.unwrap_or(VERSIONS),
+            max_renewals: file.max_renewals.unwrap_or(MAX_RENEWALS),
+            highlight_max_lines: file.highlight_max_lines.unwrap_or(HIGHLIGHT_MAX_LINES),
+            highlight_max_line_len: file.highlight_max_line_len.unwrap_or(HIGHLIGHT_MAX_LINE_LEN),
+            highlight_threads: file.highlight_threads.unwrap_or(HIGHLIGHT_THREADS),
+            language_themes: file.language_themes.unwrap_or_else(HashMap::new)
+        };
+        try!(config.validate());
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.socket.to_socket_addrs().is_err() {
+            return Err(format!("socket \"{}\" is not a valid address", self.socket));
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("tls_cert and tls_key must be set together".to_string());
+        }
+        for path in self.tls_cert.iter().chain(self.tls_key.iter()) {
+            if !Path::new(path).is_file() {
+                return Err(format!("{} does not exist", path));
+            }
+        }
+        if self.upload_dir.is_empty() || self.metadata_dir.is_empty() {
+            return Err("upload_dir and metadata_dir may not be empty".to_string());
+        }
+        if self.storage != "filesystem" && self.storage != "sqlite" {
+            return Err(format!("storage must be \"filesystem\" or \"sqlite\", not \"{}\"", self.storage));
+        }
+        if self.log_format != "plain" && self.log_format != "json" {
+            return Err(format!("log_format must be \"plain\" or \"json\", not \"{}\"", self.log_format));
+        }
+        if self.id_len < 1 || self.id_len > 32 {
+            return Err("id_len must be between 1 and 32".to_string());
+        }
+        // anything else could clash with the rest of the URL
+        if !self.id_alphabet.bytes().all(|b| b < 128 && (b as char).is_alphanumeric()) {
+            return Err("id_alphabet may only contain ASCII letters and digits".to_string());
+        }
+        let mut alphabet: Vec<u8> = self.id_alphabet.bytes().collect();
+        alphabet.sort();
+        alphabet.dedup();
+        if alphabet.len() != self.id_alphabet.len() {
+            return Err("id_alphabet may not repeat any character".to_string());
+        }
+        if alphabet.len() < 2 {
+            return Err("id_alphabet must have at least 2 characters".to_string());
+        }
+        // HMAC-SHA256 only yields 32 bytes
+        if self.key_bytes < 4 || self.key_bytes > 32 {
+            return Err("key_bytes must be between 4 and 32".to_string());
+        }
+        if self.max_paste_bytes < 1 {
+            return Err("max_paste_bytes must be at least 1".to_string());
+        }
+        if self.paste_days < 1 {
+            return Err("paste_days must be at least 1".to_string());
+        }
+        if self.rate_window_secs < 1 {
+            return Err("rate_window_secs must be at least 1".to_string());
+        }
+        if self.cleanup_interval_secs < 1 {
+            return Err("cleanup_interval_secs must be at least 1".to_string());
+        }
+        if self.auth_user.is_some() != self.auth_password_hash.is_some() {
+            return Err("auth_user and auth_password_hash must be set together".to_string());
+        }
+        if let Some(ref user) = self.auth_user {
+            if user.is_empty() || user.contains(':') {
+                return Err("auth_user may not be empty or contain a colon".to_string());
+            }
+        }
+        if self.auth_password_hash.as_ref().map_or(false, |hash| !hash.starts_with(PASSWORD_HASH_SCHEME)) {
+            return Err("auth_password_hash must be as printed by --hash-password".to_string());
+        }
+        if self.admin_password_hash.as_ref().map_or(false, |hash| !hash.starts_with(PASSWORD_HASH_SCHEME)) {
+            return Err("admin_password_hash must be as printed by --hash-password".to_string());
+        }
+        if self.highlight_threads < 1 {
+            return Err("highlight_threads must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    // the longest a paste may live, in seconds
+    pub fn max_ttl(&self) -> i64 {
+        self.paste_days as i64 * DAY_SECS
+    }
+
+    pub fn max_paste_mb(&self) -> f64 {
+        self.max_paste_bytes as f64 / 1048576.0
+    }
+}
@@ -1,60 +1,161 @@
 #[macro_use] extern crate iron;
 extern crate router;
-extern crate params;
 extern crate bodyparser;
+extern crate persistent;
+extern crate params;
 extern crate handlebars_iron;
 extern crate staticfile;
 extern crate mount;
+extern crate mime_guess;
+extern crate urlencoded;
 
+extern crate chan_signal;
 extern crate chrono;
 extern crate crypto;
+extern crate diff;
+extern crate image;
+extern crate flate2;
 #[macro_use] extern crate lazy_static;
+extern crate pulldown_cmark;
+extern crate qrcode;
 extern crate rand;
+extern crate rusqlite;
+extern crate rustc_serialize;
+extern crate rusttype;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
 extern crate syntect;
+extern crate toml;
 
-use std::collections::BTreeMap;
-use std::fs;
+mod config;
+mod meta;
+mod openapi;
+mod png;
+mod storage;
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::path::Path;
-use std::io::Write;
-use std::io::Read;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use std::thread;
 use std::time;
 
-use iron::headers::{ContentType, UserAgent, Host};
-use iron::middleware::BeforeMiddleware;
+use iron::headers::{Accept, AcceptEncoding, AcceptRanges, Allow, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec,
+                    ContentType, Encoding, EntityTag, ETag, HttpDate, IfMatch, IfModifiedSince, IfNoneMatch, Location, Quality, Range, RangeUnit,
+                    UserAgent, Host};
+use iron::mime::{Mime, TopLevel, SubLevel};
+use iron::method::Method;
+use iron::middleware::{AfterMiddleware, BeforeMiddleware, Handler};
 use iron::modifiers::Header;
 use iron::prelude::*;
+use iron::response::{BodyReader, ResponseBody};
 use iron::status;
+use iron::typemap::Key;
 
 use handlebars_iron::{HandlebarsEngine, DirectorySource, Template};
 use mount::Mount;
 use params::{Params, Value};
-use router::Router;
+use router::{NoRoute, Router};
 use staticfile::Static;
+use urlencoded::UrlEncodedQuery;
 
-use chrono::{DateTime, UTC};
+use chan_signal::Signal;
+use chrono::{DateTime, TimeZone, UTC};
 
+use crypto::digest::Digest;
 use crypto::hmac::Hmac;
 use crypto::mac::Mac;
+use crypto::pbkdf2::pbkdf2;
 use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+use flate2::Compression;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+
+use image::ColorType;
+use image::png::PNGEncoder;
+
+use pulldown_cmark::{Event, Parser, Tag};
+use pulldown_cmark::html::push_html;
+
+use qrcode::QrCode;
+use qrcode::types::Color as QrColor;
+
+use rand::{OsRng, Rng};
 
-use rand::Rng;
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::{Json, ToJson};
+
+use rusttype::{Font, FontCollection};
 
 use syntect::easy::HighlightLines;
-use syntect::highlighting::{Theme, ThemeSet, Style};
-use syntect::html::highlighted_snippet_for_string;
-use syntect::parsing::SyntaxSet;
+use syntect::highlighting::{Color, Theme, ThemeSet, Style, FONT_STYLE_BOLD, FONT_STYLE_ITALIC, FONT_STYLE_UNDERLINE};
+use syntect::parsing::{SyntaxDefinition, SyntaxSet};
 use syntect::util::as_24_bit_terminal_escaped;
 
-const SOCKET: &'static str = "127.0.0.1:3000";
+use config::{Config, DAY_SECS, PASSWORD_HASH_SCHEME};
+use meta::PasteMeta;
+use storage::{FilesystemStorage, SqliteStorage, Stats, Storage};
+
 const BASE62: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
-const ID_LEN: usize = 5;
-const KEY_BYTES: usize = 8;
-const MAX_PASTE_BYTES: usize = 2 * 1024 * 1024; // 2 MB
-const PASTE_DAYS: u32 = 30; // u32 needed for Duration checked_mul()
+const CUSTOM_ID_MIN_LEN: usize = 3;
+const CUSTOM_ID_MAX_LEN: usize = 64;
+// paths which would be shadowed by other routes
+const RESERVED_IDS: &'static [&'static str] = &["admin", "batch", "healthz", "help", "languages", "metrics", "mine", "preview", "random", "static", "stats", "tags", "themes", "webupload"];
+// polled often enough that logging them would drown out everything else
+const UNLOGGED_PATHS: &'static [&'static str] = &["/healthz", "/metrics"];
+const TITLE_MAX_LEN: usize = 200;
+const MAX_TAGS: usize = 10;
+const TAG_MAX_LEN: usize = 32;
+const TAG_PAGE_SIZE: usize = 50;
+const ADMIN_USER: &'static str = "admin";
+// for new password hashes; each records its own, so this can go up over time
+const PASSWORD_ROUNDS: u32 = 100000;
+const ADMIN_PER_PAGE: usize = 50;
+const ADMIN_MAX_PER_PAGE: usize = 1000;
+// JSON submissions may be this many times larger than a paste, since escaping
+// the paste can make it so
+const JSON_BODY_MAX_FACTOR: u64 = 6;
+const BATCH_MAX_PASTES: usize = 100;
+const TOKEN_MIN_LEN: usize = 16;
+// how long /stats is served from the cache
+const STATS_CACHE_SECS: u64 = 60;
+const NAMESPACE_MAX_LEN: usize = 16;
+// extensions suggested when asked for one we can't highlight
+const MAX_SUGGESTIONS: usize = 5;
+// smaller responses aren't worth compressing
+const GZIP_MIN_BYTES: usize = 1024;
+// bounds on the width (and height) of QR codes, in pixels
+const QR_MIN_SIZE: u32 = 64;
+const QR_MAX_SIZE: u32 = 1024;
+const QR_DEFAULT_SIZE: u32 = 256;
+const DEFAULT_THEME: &'static str = "base16-eighties.dark";
+
+// counted since the server started, and served by /metrics
+static PASTES_CREATED: AtomicUsize = ATOMIC_USIZE_INIT;
+static PASTES_RETRIEVED: AtomicUsize = ATOMIC_USIZE_INIT;
+static PASTES_DELETED: AtomicUsize = ATOMIC_USIZE_INIT;
+static PASTES_REPLACED: AtomicUsize = ATOMIC_USIZE_INIT;
 
 lazy_static! {
+    static ref CONFIG: Config = match Config::load("config.toml") {
+        Ok(config) => config,
+        Err(reason) => {
+            println!("Invalid configuration: {}", reason);
+            std::process::exit(1);
+        }
+    };
+
     static ref HMAC_KEY: String = {
         let mut file = match File::open("hmac_key.txt") {
             Ok(f) => f,
@@ -65,312 +166,4035 @@ lazy_static! {
         key
     };
 
-    static ref HL_THEME: Theme = {
-        let ts = ThemeSet::load_defaults();
-        let theme = &ts.themes["base16-eighties.dark"];
-        theme.clone()
+    // The built-in themes, along with any in CONFIG.themes_dir, named after
+    // their files. Those which can't be loaded are skipped, so that one bad
+    // file doesn't keep the server from starting.
+    static ref THEME_SET: ThemeSet = {
+        let mut themes = ThemeSet::load_defaults();
+        if Path::new(&CONFIG.themes_dir).is_dir() {
+            match ThemeSet::discover_theme_paths(&CONFIG.themes_dir) {
+                Ok(paths) => for path in paths {
+                    let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+                    match ThemeSet::get_theme(&path) {
+                        Ok(theme) => { themes.themes.insert(name, theme); },
+                        Err(e) => println!("Skipping theme {}: {:?}", path.display(), e)
+                    }
+                },
+                Err(e) => println!("Could not read themes from {}: {:?}", CONFIG.themes_dir, e)
+            }
+        }
+        themes
+    };
+
+    // None if CONFIG.png_font can't be loaded, in which case pastes can't be
+    // rendered as images
+    static ref PNG_FONT: Option<Font<'static>> = {
+        let mut bytes = Vec::new();
+        match File::open(&CONFIG.png_font).and_then(|mut f| f.read_to_end(&mut bytes)) {
+            Ok(_) => FontCollection::from_bytes(bytes).into_font(),
+            Err(_) => None
+        }
+    };
+
+    static ref STORAGE: Box<Storage> = {
+        let storage = match CONFIG.storage.as_str() {
+            "sqlite" => SqliteStorage::new(&CONFIG.database, CONFIG.max_ttl())
+                .map(|storage| Box::new(storage) as Box<Storage>),
+            _ => FilesystemStorage::new(&CONFIG.upload_dir, &CONFIG.metadata_dir, CONFIG.max_ttl())
+                .map(|storage| Box::new(storage) as Box<Storage>)
+        };
+        match storage {
+            Ok(storage) => storage,
+            Err(e) => {
+                println!("Could not open paste storage: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    // Bytes used by stored pastes. Kept up to date as pastes come and
+    // go, and recounted from scratch by the cleanup thread.
+    static ref DISK_USAGE: Mutex<usize> = Mutex::new(0);
+
+    // Held while updating existing metadata, so concurrent updates
+    // (e.g. two views at once) don't lose each other's changes.
+    static ref META_LOCK: Mutex<()> = Mutex::new(());
+
+    // Read-held by handlers while they write to storage, and write-held while
+    // shutting down, so the server never exits halfway through writing a paste.
+    static ref WRITES: RwLock<()> = RwLock::new(());
+
+    // Held from counting a submitter's pastes until their new one is stored,
+    // see check_paste_cap().
+    static ref SUBMITTER_LOCK: Mutex<()> = Mutex::new(());
+
+    // The sources of the .sublime-syntax files in CONFIG.syntaxes_dir, or at
+    // least those which could be loaded; the rest are skipped with a warning,
+    // so that one bad file doesn't keep the server from starting. Each
+    // highlighting thread loads them again, since syntaxes can't be shared.
+    static ref CUSTOM_SYNTAXES: Vec<String> = {
+        let mut sources = Vec::new();
+        let files = match std::fs::read_dir(&CONFIG.syntaxes_dir) {
+            Ok(files) => files,
+            Err(_) => return sources // there needn't be any
+        };
+        for file in files {
+            let path = match file {
+                Ok(file) => file.path(),
+                Err(e) => {
+                    println!("Skipping unreadable entry in {}: {}", CONFIG.syntaxes_dir, e);
+                    continue;
+                }
+            };
+            if path.extension().map_or(true, |ext| ext != "sublime-syntax") {
+                continue;
+            }
+            let mut source = String::new();
+            if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut source)) {
+                println!("Skipping syntax {}: {}", path.display(), e);
+                continue;
+            }
+            match SyntaxDefinition::load_from_str(&source, false) {
+                Ok(_) => sources.push(source),
+                Err(e) => println!("Skipping syntax {}: {:?}", path.display(), e)
+            }
+        }
+        sources
+    };
+
+    // The last figures stats() worked out, and when.
+    static ref STATS: Mutex<Option<(time::Instant, Stats)>> = Mutex::new(None);
+}
+
+// SyntaxSet is neither Send nor Sync (it is full of Rcs), so it can't be shared
+// between threads, even behind a Mutex; see
+// https://github.com/trishume/syntect/issues/20. Rather than each of the
+// server's many threads loading a set of its own, which took a long time and
+// a lot of memory, a few threads own one each, and everything needing one is
+// sent to them to be done, see with_syntax_set().
+lazy_static! {
+    static ref SYNTAX_JOBS: Mutex<mpsc::Sender<SyntaxJob>> = {
+        let (sender, receiver) = mpsc::channel::<SyntaxJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..CONFIG.highlight_threads {
+            let receiver = receiver.clone();
+            thread::spawn(move || {
+                let ss = load_syntax_set();
+                loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        // the job's caller finds out about any panic, see with_syntax_set()
+                        Ok(mut job) => { let _ = panic::catch_unwind(AssertUnwindSafe(|| job(&ss))); },
+                        Err(_) => break
+                    }
+                }
+            });
+        }
+        Mutex::new(sender)
     };
 }
 
-// SyntaxSet does not implement Copy/Sync, so we do it like this.
-// see https://github.com/trishume/syntect/issues/20
-thread_local! {
-    static SYNTAX_SET: SyntaxSet = SyntaxSet::load_defaults_nonewlines();
+type SyntaxJob = Box<FnMut(&SyntaxSet) + Send>;
+
+fn load_syntax_set() -> SyntaxSet {
+    let mut ss = SyntaxSet::load_defaults_nonewlines();
+    for source in CUSTOM_SYNTAXES.iter() {
+        if let Ok(syntax) = SyntaxDefinition::load_from_str(source, false) {
+            ss.add_syntax(syntax);
+        }
+    }
+    ss.link_syntaxes();
+    ss
+}
+
+// Runs f on one of the highlighting threads, waiting for it to be done. Since
+// f has to be sent there, it can't borrow anything, so callers hand it copies.
+fn with_syntax_set<T, F>(f: F) -> T
+    where T: Send + 'static, F: FnOnce(&SyntaxSet) -> T + Send + 'static
+{
+    let (done, result) = mpsc::channel();
+    let mut f = Some(f);
+    let job: SyntaxJob = Box::new(move |ss| {
+        if let Some(f) = f.take() {
+            let _ = done.send(f(ss));
+        }
+    });
+    SYNTAX_JOBS.lock().unwrap().send(job).expect("highlighting threads have stopped");
+    // should f panic, the sender is dropped without anything being sent
+    result.recv().expect("highlighting failed")
 }
 
 #[derive(Debug)]
 enum HighlightedText {
     Terminal(String),
-    Html(String),
+    Html(Vec<String>), // one entry per line
     Error(String)
 }
 
+fn templates() -> HandlebarsEngine {
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(DirectorySource::new("./templates/", ".hbs")));
+
+    // load templates from all registered sources
+    if let Err(r) = hbse.reload() {
+        panic!("{}", r.cause);
+    }
+    hbse
+}
+
+struct HtmlLine {
+    number: usize,
+    html: String,
+    selected: bool
+}
 
+impl ToJson for HtmlLine {
+    fn to_json(&self) -> Json {
+        let mut line = BTreeMap::new();
+        line.insert("number".to_string(), self.number.to_json());
+        line.insert("html".to_string(), self.html.to_json());
+        line.insert("selected".to_string(), self.selected.to_json());
+        Json::Object(line)
+    }
+}
 
+// Linked both before and after the handlers, so that each request is logged
+// as it comes in and again with its outcome. The two are tied together by a
+// short request ID. JSON logs are only written once the response is known, so
+// that each request is a single object (one per line).
 struct LoggingMiddleware;
+
+// stashed in req.extensions by the before hook
+struct RequestLog;
+impl Key for RequestLog { type Value = (String, DateTime<UTC>, time::Instant); }
+
+impl LoggingMiddleware {
+    fn log_response(&self, req: &Request, status: Option<status::Status>) {
+        let (id, utc, start) = match req.extensions.get::<RequestLog>() {
+            Some(log) => log.clone(),
+            None => return // unlogged
+        };
+        let elapsed = start.elapsed();
+        let latency_ms = elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6;
+        if CONFIG.log_format == "json" {
+            let mut entry = BTreeMap::new();
+            entry.insert("request_id".to_string(), id.to_json());
+            entry.insert("timestamp".to_string(), utc.to_rfc3339().to_json());
+            entry.insert("remote_addr".to_string(), req.remote_addr.to_string().to_json());
+            entry.insert("method".to_string(), req.method.to_string().to_json());
+            entry.insert("path".to_string(), format!("/{}", req.url.path().join("/")).to_json());
+            entry.insert("status".to_string(), status.map(|status| status.to_u16()).to_json());
+            entry.insert("latency_ms".to_string(), latency_ms.to_json());
+            println!("{}", Json::Object(entry));
+        } else {
+            let status = status.map_or("no status".to_string(), |status| status.to_string());
+            println!("[{}] [{}] [{}]: {} in {:.1} ms", req.remote_addr, UTC::now().format("%Y-%m-%d %H:%M:%S"),
+                     id, status, latency_ms);
+        }
+    }
+}
+
 impl BeforeMiddleware for LoggingMiddleware {
     fn before(&self, req: &mut Request) -> IronResult<()> {
+        if is_unlogged(req) {
+            return Ok(());
+        }
+        let id = generate_id(8, CONFIG.id_alphabet.as_bytes());
         let utc: DateTime<UTC> = UTC::now();
-        println!("[{}] [{}]: {}", req.remote_addr, utc.format("%Y-%m-%d %H:%M:%S"), req.url);
+        if CONFIG.log_format == "plain" {
+            println!("[{}] [{}] [{}]: {} {}", req.remote_addr, utc.format("%Y-%m-%d %H:%M:%S"), id, req.method, req.url);
+        }
+        req.extensions.insert::<RequestLog>((id, utc, time::Instant::now()));
         Ok(())
     }
 }
 
+impl AfterMiddleware for LoggingMiddleware {
+    fn after(&self, req: &mut Request, resp: Response) -> IronResult<Response> {
+        self.log_response(req, resp.status);
+        Ok(resp)
+    }
 
-fn main() {
-    if HMAC_KEY.as_bytes().len() == 0 {
-        println!("You must set a key in hmac_key.txt");
-        std::process::exit(1);
+    // e.g. requests turned away by the rate limiter
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.log_response(req, err.response.status);
+        Err(err)
     }
+}
 
-    let mut router = Router::new();
-    router.get("/", usage, "index");
-    router.get("/help", help, "help");
-    router.get("/:paste_id", retrieve, "retrieve");
-    router.get("/:paste_id/:lang", retrieve, "retrieve_lang");
-    router.delete("/:paste_id", delete, "delete_nokey");
-    router.delete("/:paste_id/:key", delete, "delete");
-    router.put("/:paste_id/:key", replace, "replace");
-    router.post("/", submit, "submit");
+fn is_unlogged(req: &Request) -> bool {
+    let path = format!("/{}", req.url.path().join("/"));
+    UNLOGGED_PATHS.contains(&path.as_str())
+}
 
-    let mut mount = Mount::new();
-    mount.mount("/", router)
-         .mount("/webupload", Static::new(Path::new("./static/webupload.html")));
+// Takes the namespace out of the paths of namespaced pastes before they are
+// routed, so that e.g. /team/abc123/rs is routed just as /abc123/rs would be,
+// and paste_id() puts it back. Namespaces and top-level paste IDs are kept
+// from clashing when pastes are created, see store_paste().
+struct NamespaceMiddleware;
 
-    let mut hbse = HandlebarsEngine::new();
-    hbse.add(Box::new(DirectorySource::new("./templates/", ".hbs")));
+struct Namespace;
+impl Key for Namespace { type Value = String; }
 
-    // load templates from all registered sources
-    if let Err(r) = hbse.reload() {
-        panic!("{}", r.cause);
+impl BeforeMiddleware for NamespaceMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let path: Vec<String> = req.url.path().iter().map(|segment| segment.to_string()).collect();
+        if path.len() < 2 || path[1].is_empty() || validate_namespace(&path[0]).is_err() {
+            return Ok(());
+        }
+        if !itry!(STORAGE.is_namespace(&path[0])) {
+            return Ok(());
+        }
+        req.url.as_mut().set_path(&path[1..].join("/"));
+        req.extensions.insert::<Namespace>(path[0].clone());
+        Ok(())
     }
+}
 
-    let mut chain = Chain::new(mount);
-    chain.link_before(LoggingMiddleware);
-    chain.link_after(hbse);
-    let server = Iron::new(chain).http(SOCKET).unwrap();
+// Records the method and path of every route as it is added to the router,
+// so that MethodNotAllowedMiddleware knows which methods each path supports.
+struct Routes {
+    router: Router,
+    globs: Vec<(Method, &'static str)>
+}
+
+impl Routes {
+    fn new() -> Routes {
+        Routes { router: Router::new(), globs: Vec::new() }
+    }
 
-    println!("Listening on http://{} ({})", SOCKET, server.socket);
+    fn route<H: Handler>(&mut self, method: Method, glob: &'static str, handler: H, id: &str) {
+        self.globs.push((method.clone(), glob));
+        self.router.route(method, glob, handler, id);
+    }
 
-    // every day, delete pastes > PASTE_DAYS days old
-    thread::spawn(move || {
-        let one_day = time::Duration::from_secs(60*60*24);
-        let thirty_days = one_day * PASTE_DAYS;
-        println!("Pastes are deleted when they are {} days old.", PASTE_DAYS);
-        loop {
-            let now = time::SystemTime::now();
-            let files = fs::read_dir("./uploads").unwrap();
-            for file in files {
-                let path = file.unwrap().path();
-                let attr = fs::metadata(&path).unwrap();
-                let last_modified = attr.modified().expect("reading last modified time");
-                if now.duration_since(last_modified).unwrap() > thirty_days {
-                    fs::remove_file(path).expect("deleting file");
-                }
+    fn get<H: Handler>(&mut self, glob: &'static str, handler: H, id: &str) { self.route(Method::Get, glob, handler, id) }
+    fn head<H: Handler>(&mut self, glob: &'static str, handler: H, id: &str) { self.route(Method::Head, glob, handler, id) }
+    fn delete<H: Handler>(&mut self, glob: &'static str, handler: H, id: &str) { self.route(Method::Delete, glob, handler, id) }
+    fn put<H: Handler>(&mut self, glob: &'static str, handler: H, id: &str) { self.route(Method::Put, glob, handler, id) }
+    fn patch<H: Handler>(&mut self, glob: &'static str, handler: H, id: &str) { self.route(Method::Patch, glob, handler, id) }
+    fn post<H: Handler>(&mut self, glob: &'static str, handler: H, id: &str) { self.route(Method::Post, glob, handler, id) }
+    fn options<H: Handler>(&mut self, glob: &'static str, handler: H, id: &str) { self.route(Method::Options, glob, handler, id) }
+}
+
+// Turns the router's 404 for a path which exists, but not with the method it
+// was requested with, into a 405 listing the methods it does support.
+struct MethodNotAllowedMiddleware {
+    globs: Vec<(Method, &'static str)>
+}
+
+impl MethodNotAllowedMiddleware {
+    fn allowed(&self, path: &[&str]) -> Vec<Method> {
+        let mut allowed: Vec<Method> = Vec::new();
+        for &(ref method, glob) in &self.globs {
+            if glob_matches(glob, path) && !allowed.contains(method) {
+                allowed.push(method.clone());
             }
-            thread::sleep(one_day);
         }
-    });
+        allowed
+    }
 }
 
+impl AfterMiddleware for MethodNotAllowedMiddleware {
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        if !err.error.is::<NoRoute>() {
+            return Err(err);
+        }
+        let allowed = self.allowed(&req.url.path());
+        if allowed.is_empty() {
+            return Err(err);
+        }
+        let mut resp = Response::with((status::MethodNotAllowed,
+                                       format!("Method {} is not allowed for this path\n", req.method)));
+        resp.headers.set(Allow(allowed));
+        Ok(resp)
+    }
+}
 
-fn usage(req: &mut Request) -> IronResult<Response> {
-    let mut resp = Response::new();
-    resp.set_mut(Header(ContentType::plaintext()));
+// Whether a router glob such as "/:paste_id/raw" matches the path segments.
+fn glob_matches(glob: &str, path: &[&str]) -> bool {
+    let segments: Vec<&str> = glob.trim_left_matches('/').split('/').collect();
+    segments.len() == path.len() && segments.iter().zip(path).all(|(segment, part)| {
+        if segment.starts_with(':') { !part.is_empty() } else { segment == part }
+    })
+}
 
-    let mut data = BTreeMap::new();
-    data.insert("host".to_string(), get_hostname(req));
-    data.insert("id".to_string(), "vxcRz".to_string());
-    data.insert("key".to_string(), "a7772362cf6e2c36".to_string());
-    data.insert("ext".to_string(), "rs".to_string());
+// Lets browsers on the configured origins use the API, by answering their
+// preflight requests (see preflight()) and marking the responses as shareable.
+struct CorsMiddleware;
 
-    resp.set_mut(Template::new("index", data)).set_mut(status::Ok);
-    Ok(resp)
+impl CorsMiddleware {
+    fn allow(&self, req: &Request, resp: &mut Response) {
+        let origin = match req.headers.get_raw("Origin").and_then(|values| values.first()) {
+            Some(origin) => String::from_utf8_lossy(origin).into_owned(),
+            None => return
+        };
+        if !CONFIG.cors_origins.iter().any(|allowed| *allowed == origin || allowed == "*") {
+            return;
+        }
+        resp.headers.set_raw("Access-Control-Allow-Origin", vec![origin.into_bytes()]);
+        add_vary(resp, "Origin");
+        resp.headers.set_raw("Access-Control-Allow-Methods", vec![b"GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS".to_vec()]);
+        resp.headers.set_raw("Access-Control-Allow-Headers", vec![b"Authorization, Content-Type, X-Filename, X-Paste-Password".to_vec()]);
+        resp.headers.set_raw("Access-Control-Expose-Headers",
+                             vec![b"ETag, Link, Location, X-View-Count, X-Burn-After-Reading, X-Detected-Language".to_vec()]);
+    }
 }
 
-fn help(req: &mut Request) -> IronResult<Response> {
-    // TODO: combine this with usage above.
-    let mut resp = Response::new();
-    resp.set_mut(Header(ContentType::plaintext()));
-
-    let mut data = BTreeMap::new();
-    data.insert("host".to_string(), get_hostname(req));
-    data.insert("id".to_string(), "vxcRz".to_string());
-    data.insert("key".to_string(), "a7772362cf6e2c36".to_string());
-    data.insert("ext".to_string(), "rs".to_string());
+impl AfterMiddleware for CorsMiddleware {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        self.allow(req, &mut resp);
+        Ok(resp)
+    }
 
-    resp.set_mut(Template::new("help", data)).set_mut(status::Ok);
-    Ok(resp)
+    // so that browsers can see why a request failed, e.g. the rate limit
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.allow(req, &mut err.response);
+        Err(err)
+    }
 }
 
-// Note: webform is multipart/form-data so that raw post data yields None.
-// Doing so allows us to unambiguously differentiate between a "data"
-// variable (from the web form) and a raw post that happens contain
-// urlencoded query params. Is this poor style?
-// TODO: determine whether bodyparser can replace Params ("parses body into a struct using Serde")
-fn submit(req: &mut Request) -> IronResult<Response> {
-    // get paste contents, either raw post or data param
-    let raw_body = match req.get::<bodyparser::Raw>() {
-        Ok(body) => body,
-        Err(e) => return Ok(Response::with((status::BadRequest, format!("Invalid paste data submitted: {}.\n", e.detail))))
-    };
-    let paste = match raw_body {
-        Some(paste) => paste,
-        None => {
-            // TODO: determine why this needs .get_ref, when we used .get above for raw post
-            let params = req.get_ref::<Params>().unwrap();
-            match params.find(&["data"]) {
-                Some(&Value::String(ref data)) => data.clone().to_string(),
-                _ => return Ok(Response::with((status::BadRequest, "No paste data submitted.\n")))
-            }
+// Gives API clients (those asking for JSON) every error as
+// {"error": {"code": "not_found", "message": "..."}}, rather than the plain
+// text the handlers reply with, so that they can all be handled alike. Errors
+// which are JSON already, e.g. 422s listing what is wrong with each field, are
+// left alone.
+struct JsonErrorMiddleware;
+
+impl JsonErrorMiddleware {
+    fn convert(&self, req: &Request, resp: &mut Response) -> io::Result<()> {
+        let status = match resp.status {
+            Some(status) if status.is_client_error() || status.is_server_error() => status,
+            _ => return Ok(())
+        };
+        if !wants_json(req) {
+            return Ok(());
         }
-    };
-    // verify max size before saving it
-    if paste.len() > MAX_PASTE_BYTES {
-        return Ok(Response::with((status::BadRequest, format!("Pastes may not be more than {} MB.\n", MAX_PASTE_BYTES/1048576))))
-    }
-    // get paste ID and URL
-    let mut id: String;
-    let mut path: String;
-    let mut double_id_len = ID_LEN * 2; // so we increase by 1 every two loops
-    loop {
-        id = generate_id(double_id_len / 2);
-        path = format!("uploads/{id}", id = id);
-        if !Path::new(&path).exists() {
-            break;
+        match resp.headers.get::<ContentType>() {
+            None | Some(&ContentType(Mime(TopLevel::Text, SubLevel::Plain, _))) => {},
+            _ => return Ok(())
         }
-        double_id_len += 1;
+        let mut body = Vec::new();
+        if let Some(mut old) = resp.body.take() {
+            try!(old.write_body(&mut ResponseBody::new(&mut body)));
+        }
+        let message = String::from_utf8_lossy(&body).trim().to_string();
+        let envelope = error_envelope(status, &message).to_string();
+        resp.headers.set(ContentType::json());
+        resp.headers.set(ContentLength(envelope.len() as u64));
+        resp.body = Some(Box::new(envelope));
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for JsonErrorMiddleware {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        itry!(self.convert(req, &mut resp));
+        Ok(resp)
     }
-    let url = format!("https://{host}/{id}", host = get_hostname(req), id = id);
 
-    let mut f = itry!(File::create(path));
-    itry!(f.write_all(paste.as_bytes()));
-    Ok(Response::with((status::Created, format!(
-        "View URL: {url}\nEdit URL: {url}/{key}\n\nThis paste will be deleted in {days} days.\n",
-        url = url, key = gen_key(&id), days = PASTE_DAYS))))
+    // e.g. requests turned away by the rate limiter, or for paths which don't exist
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        itry!(self.convert(req, &mut err.response));
+        Err(err)
+    }
 }
 
-fn retrieve(req: &mut Request) -> IronResult<Response> {
-    let params = req.extensions.get::<Router>().unwrap();
-    // TODO: "ref" appears unnecessary -- determine why it's here
-    let id = &params.find("paste_id").unwrap_or("");
-    let lang = params.find("lang");
+// The code is the status's reason phrase in snake case, e.g. "payload_too_large".
+fn error_envelope(status: status::Status, message: &str) -> Json {
+    let reason = status.canonical_reason().unwrap_or("error");
+    let code: String = reason.to_lowercase().chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    // e.g. for a 404 from the router, which has no message of its own
+    let message = if message.is_empty() { reason } else { message };
+    let mut error = BTreeMap::new();
+    error.insert("code".to_string(), code.to_json());
+    error.insert("message".to_string(), message.to_json());
+    let mut envelope = BTreeMap::new();
+    envelope.insert("error".to_string(), Json::Object(error));
+    Json::Object(envelope)
+}
 
-    let mut f = match File::open(format!("uploads/{id}", id = id)) {
-        Ok(f) => f,
-        Err(_) => return Ok(Response::with((status::NotFound, format!("Paste {} does not exist\n", id))))
-    };
+// Compresses rendered pages (HTML and JSON) for clients which accept gzip.
+// Everything else is either small, already compressed (e.g. PNGs), or streamed
+// as it is (raw pastes), which compressing would mean buffering.
+struct GzipMiddleware;
 
-    let mut buffer = String::new();
-    itry!(f.read_to_string(&mut buffer));
+impl AfterMiddleware for GzipMiddleware {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        let compressible = match resp.headers.get::<ContentType>() {
+            Some(&ContentType(Mime(TopLevel::Text, SubLevel::Html, _))) => true,
+            Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => true,
+            _ => false
+        };
+        if !compressible || resp.body.is_none() {
+            return Ok(resp);
+        }
+        // whether or not this particular response ends up compressed
+        add_vary(&mut resp, "Accept-Encoding");
+        let accepts_gzip = match req.headers.get::<AcceptEncoding>() {
+            Some(&AcceptEncoding(ref items)) => items.iter().any(|item| item.item == Encoding::Gzip && item.quality != Quality(0)),
+            None => false
+        };
+        if !accepts_gzip {
+            return Ok(resp);
+        }
+        let mut body = Vec::new();
+        itry!(resp.body.take().unwrap().write_body(&mut ResponseBody::new(&mut body)));
+        if body.len() < GZIP_MIN_BYTES {
+            resp.headers.set(ContentLength(body.len() as u64));
+            resp.body = Some(Box::new(body));
+            return Ok(resp);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        itry!(encoder.write_all(&body));
+        let compressed = itry!(encoder.finish());
+        resp.headers.set(ContentLength(compressed.len() as u64));
+        resp.headers.set_raw("Content-Encoding", vec![b"gzip".to_vec()]);
+        resp.body = Some(Box::new(compressed));
+        Ok(resp)
+    }
+}
 
-    match lang {
-        Some(lang) => {
-            // syntax highlighting
-            let html_output = !is_curl(req);
-            match highlight(buffer, lang, html_output) {
-                HighlightedText::Terminal(s) => Ok(Response::with((status::Ok, s))),
-                HighlightedText::Html(s) => {
-                    let mut resp = Response::new();
-                    let mut data = BTreeMap::new();
-                    data.insert("paste".to_string(), s);
-                    resp.set_mut(Template::new("paste_html", data)).set_mut(status::Ok);
-                    Ok(resp)
-                },
-                HighlightedText::Error(s) => Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", s))))
-            }
-        },
-        // no syntax highlighting
-        None => {
-            Ok(Response::with((status::Ok, buffer)))
+// Responses to HEAD requests are built as for GET (apart from the streamed raw
+// pastes, which set their own Content-Length), and then have their body
+// measured and dropped here, so that the Content-Length is that of the body GET
+// would get. Linked after everything else which changes the body.
+struct HeadMiddleware;
+
+impl AfterMiddleware for HeadMiddleware {
+    fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
+        if req.method == Method::Head {
+            itry!(drop_body(&mut resp));
         }
+        Ok(resp)
     }
 }
 
-fn delete(req: &mut Request) -> IronResult<Response> {
-    let (id, path) = match validate_key_id(req) {
-        Ok((id, path)) => (id, path),
-        Err(reason) => return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))))
-    };
-    // delete file
-    itry!(fs::remove_file(path));
-    Ok(Response::with((status::Ok, format!("Paste {} deleted.\n", id))))
+// Iron would give a response without a body a Content-Length of 0 whatever
+// it was set to, so it gets an empty one instead.
+fn drop_body(resp: &mut Response) -> io::Result<()> {
+    if let Some(mut body) = resp.body.take() {
+        let mut buffer = Vec::new();
+        try!(body.write_body(&mut ResponseBody::new(&mut buffer)));
+        resp.headers.set(ContentLength(buffer.len() as u64));
+    } else if !resp.headers.has::<ContentLength>() {
+        resp.headers.set(ContentLength(0));
+    }
+    resp.body = Some(Box::new(Vec::new()));
+    Ok(())
 }
 
-fn replace(req: &mut Request) -> IronResult<Response> {
-    let (id, path) = match validate_key_id(req) {
-        Ok((id, path)) => (id, path),
-        Err(reason) => return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))))
+fn add_vary(resp: &mut Response, header: &str) {
+    let vary = match resp.headers.get_raw("Vary").and_then(|values| values.first()) {
+        Some(vary) => format!("{}, {}", String::from_utf8_lossy(vary), header),
+        None => header.to_string()
     };
-    // write body
-    let paste = itry!(req.get::<bodyparser::Raw>()).unwrap();
-    // verify max size before saving it
-    if paste.len() > MAX_PASTE_BYTES {
-        return Ok(Response::with((status::BadRequest, format!("Pastes may not be more than {} MB.\n", MAX_PASTE_BYTES/1048576))))
+    resp.headers.set_raw("Vary", vec![vary.into_bytes()]);
+}
+
+#[derive(Debug)]
+struct Unauthenticated;
+
+impl fmt::Display for Unauthenticated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
     }
-    let mut f = itry!(File::create(path));
-    itry!(f.write_all(paste.as_bytes()));
-    Ok(Response::with((status::Ok, format!(
-        "https://{host}/{id} overwritten.\n\nThis paste will be deleted in {days} days.\n",
-        host=get_hostname(req), id = id, days = PASTE_DAYS))))
 }
 
+impl Error for Unauthenticated {
+    fn description(&self) -> &str {
+        "missing or invalid basic auth credentials"
+    }
+}
 
+// Closes the whole instance, apart from /healthz, to anybody without the
+// configured basic auth credentials (or the admin's, see is_admin()). Does
+// nothing unless they are configured.
+struct BasicAuthMiddleware;
 
+impl BeforeMiddleware for BasicAuthMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let (user, hash) = match (CONFIG.auth_user.as_ref(), CONFIG.auth_password_hash.as_ref()) {
+            (Some(user), Some(hash)) => (user, hash),
+            _ => return Ok(())
+        };
+        if req.url.path() == ["healthz"] {
+            return Ok(());
+        }
+        let valid = match basic_credentials(req) {
+            Some((ref given_user, ref password)) if given_user == user => credential_matches(password, hash),
+            _ => false
+        };
+        if valid || is_admin(req) {
+            return Ok(());
+        }
+        let mut resp = Response::with((status::Unauthorized, "This pastebin requires a username and password.\n"));
+        resp.headers.set_raw("WWW-Authenticate", vec![b"Basic realm=\"pastebin\"".to_vec()]);
+        Err(IronError { error: Box::new(Unauthenticated), response: resp })
+    }
+}
 
+// Whether the request carries the basic auth credentials of the user "admin",
+// whose password is configured as admin_password_hash.
+fn is_admin(req: &Request) -> bool {
+    match (basic_credentials(req), CONFIG.admin_password_hash.as_ref()) {
+        (Some((ref user, ref password)), Some(hash)) if user == ADMIN_USER => credential_matches(password, hash),
+        _ => false
+    }
+}
 
-fn validate_key_id(req: &Request) -> Result<(String, String), String> {
-    let params = req.extensions.get::<Router>().unwrap();
-    let id = params.find("paste_id").unwrap_or("").to_string();
-    let path = format!("uploads/{id}", id = id);
-    if !Path::new(&path).exists() {
-        return Err(format!("Paste {} does not exist", id));
+// Checks a password against a hash as printed by --hash-password, i.e.
+// "pbkdf2-sha256$<rounds>$<salt>$<hash>".
+fn credential_matches(password: &str, hash: &str) -> bool {
+    let parts: Vec<&str> = hash.splitn(4, '$').collect();
+    if parts.len() != 4 || !hash.starts_with(PASSWORD_HASH_SCHEME) {
+        return false;
     }
-    let key = params.find("key").unwrap_or("");
-    if key != gen_key(&id) {
-        return Err("Key is not valid".to_string());
+    match parts[1].parse() {
+        Ok(rounds) => fixed_time_eq(credential_hash(password, parts[2], rounds).as_bytes(), hash.as_bytes()),
+        Err(_) => false
     }
-    Ok((id, path))
 }
 
-fn generate_id(size: usize) -> String {
-    let mut id = String::with_capacity(size);
-    let mut rng = rand::thread_rng();
-    for _ in 0..size {
-        id.push(BASE62[rng.gen::<usize>() % 62] as char);
+// Unlike key_hash(), which only ever sees random keys, this is slow on purpose,
+// since people's passwords are far easier to guess.
+fn credential_hash(password: &str, salt: &str, rounds: u32) -> String {
+    let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+    let mut hash = [0u8; 32];
+    pbkdf2(&mut mac, salt.as_bytes(), rounds, &mut hash);
+    let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}${}${}", PASSWORD_HASH_SCHEME, rounds, salt, hex)
+}
+
+// Checks a random edit key against what key_hash() gave for it.
+fn hash_matches(secret: &str, hash: &str) -> bool {
+    // stored as "<salt>$<hash>", see key_hash()
+    match hash.find('$') {
+        Some(i) => fixed_time_eq(key_hash(secret, &hash[..i]).as_bytes(), hash.as_bytes()),
+        None => false
     }
-    id
 }
 
-fn gen_key(input: &str) -> String {
-    let mut hmac = Hmac::new(Sha256::new(), HMAC_KEY.as_bytes());
-    hmac.input(input.as_bytes());
-    let hmac_result = hmac.result();
-    let key: String = hmac_result.code().iter()
-        .take(KEY_BYTES)
-        .map(|b| format!("{:02X}", b))
-        .collect();
-    key.to_lowercase()
+// The user and password of an "Authorization: Basic" header.
+fn basic_credentials(req: &Request) -> Option<(String, String)> {
+    let value = match req.headers.get_raw("Authorization").and_then(|values| values.first()) {
+        Some(value) => value,
+        None => return None
+    };
+    let value = match str::from_utf8(value) {
+        Ok(value) if value.starts_with("Basic ") => value["Basic ".len()..].trim(),
+        _ => return None
+    };
+    let decoded = match value.from_base64().ok().and_then(|decoded| String::from_utf8(decoded).ok()) {
+        Some(decoded) => decoded,
+        None => return None
+    };
+    decoded.find(':').map(|i| (decoded[..i].to_string(), decoded[i + 1..].to_string()))
 }
 
-fn get_hostname(req: &Request) -> String {
-    match req.headers.get::<Host>() {
-        Some(h) => {
-            let mut host = h.hostname.to_owned();
-            if let Some(port) = h.port {
-                host = host + ":" + &port.to_string();
-            }
-            return host
-        },
-        _ => SOCKET.to_owned()
+// Prints the hash of a password read from stdin, for auth_password_hash.
+fn print_password_hash() {
+    let mut password = String::new();
+    if let Err(e) = io::stdin().read_line(&mut password) {
+        println!("Reading the password: {}", e);
+        std::process::exit(1);
+    }
+    let password = password.trim_right_matches(|c| c == '\n' || c == '\r');
+    if password.is_empty() {
+        println!("Usage: echo \"<password>\" | pastebin --hash-password");
+        std::process::exit(1);
     }
+    // not random_key(), which would need the configuration, which may not be valid without the hash
+    let mut rng = OsRng::new().expect("opening the OS random number generator");
+    let salt: String = (0..16).map(|_| format!("{:02x}", rng.gen::<u8>())).collect();
+    println!("{}", credential_hash(password, &salt, PASSWORD_ROUNDS));
 }
 
-fn is_curl(req: &Request) -> bool {
-    match req.headers.get::<UserAgent>() {
-        Some(&UserAgent(ref string)) => string.starts_with("curl/"),
-        _ => true
+#[derive(Debug)]
+struct RateLimited;
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
     }
 }
 
-fn highlight(buffer: String, lang: &str, html: bool) -> HighlightedText {
-    SYNTAX_SET.with(|ss| {
-        let syntax = ss.find_syntax_by_extension(lang).unwrap_or_else(|| ss.find_syntax_plain_text());
-        if syntax.name == "Plain Text" {
-            return HighlightedText::Error(format!("Requested highlight \"{}\" not available", lang));
+impl Error for RateLimited {
+    fn description(&self) -> &str {
+        "rate limit exceeded"
+    }
+}
+
+struct RateLimitState {
+    // when each client's current window started, and how many writes they made in it
+    clients: HashMap<IpAddr, (time::Instant, u32)>,
+    last_pruned: time::Instant
+}
+
+// Limits each client to CONFIG.rate_limit pastes created or changed per window.
+struct RateLimitMiddleware {
+    state: Mutex<RateLimitState>
+}
+
+impl RateLimitMiddleware {
+    fn new() -> RateLimitMiddleware {
+        RateLimitMiddleware {
+            state: Mutex::new(RateLimitState {
+                clients: HashMap::new(),
+                last_pruned: time::Instant::now()
+            })
         }
-        if html {
-            HighlightedText::Html(highlighted_snippet_for_string(&buffer, syntax, &HL_THEME))
-        } else {
-            let mut highlighter = HighlightLines::new(syntax, &HL_THEME);
-            let mut output = String::new();
+    }
+}
+
+impl BeforeMiddleware for RateLimitMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if CONFIG.rate_limit == 0 {
+            return Ok(());
+        }
+        match req.method {
+            Method::Post | Method::Put | Method::Patch => {},
+            _ => return Ok(())
+        }
+        let window = time::Duration::from_secs(CONFIG.rate_window_secs);
+        let now = time::Instant::now();
+        let mut state = self.state.lock().unwrap();
+        // forget clients whose window has passed, so one-off clients don't pile up
+        if now.duration_since(state.last_pruned) > window {
+            state.clients.retain(|_, &mut (start, _)| now.duration_since(start) <= window);
+            state.last_pruned = now;
+        }
+        let client = state.clients.entry(req.remote_addr.ip()).or_insert((now, 0));
+        if now.duration_since(client.0) > window {
+            *client = (now, 0);
+        }
+        client.1 += 1;
+        if client.1 > CONFIG.rate_limit {
+            return Err(IronError::new(RateLimited, (status::TooManyRequests, format!(
+                "Too many requests: at most {} pastes may be submitted every {} seconds.\n",
+                CONFIG.rate_limit, CONFIG.rate_window_secs))));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct BodyRefused;
+
+impl fmt::Display for BodyRefused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for BodyRefused {
+    fn description(&self) -> &str {
+        "request body refused"
+    }
+}
+
+// Turns away bodies larger than any handler accepts before anything reads them.
+// Handlers reading the body themselves stop at their own limit anyway (see
+// read_limited()), but forms are parsed by params, which reads them whole:
+// urlencoded ones through bodyparser, whose limit is set alongside this, and
+// multipart ones into temporary files. Since nothing would stop the latter,
+// they have to say how long they are up front, as curl and browsers do.
+struct BodyLimitMiddleware;
+
+impl BeforeMiddleware for BodyLimitMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match req.headers.get::<ContentLength>() {
+            Some(&ContentLength(len)) if len > max_body_bytes() => {
+                Err(IronError::new(BodyRefused, too_large()))
+            },
+            None if is_multipart(req) => {
+                Err(IronError::new(BodyRefused, (status::LengthRequired, "Forms must be sent with a Content-Length.\n")))
+            },
+            _ => match body_encoding(req) {
+                Some(ref encoding) if encoding != "gzip" && encoding != "deflate" => {
+                    Err(IronError::new(BodyRefused, (status::UnsupportedMediaType, format!(
+                        "Unsupported Content-Encoding \"{}\": bodies may only be compressed with gzip or deflate.\n", encoding))))
+                },
+                // forms are read by params, which wouldn't decompress them
+                Some(_) if is_multipart(req) => {
+                    Err(IronError::new(BodyRefused, (status::UnsupportedMediaType, "Forms may not be compressed.\n")))
+                },
+                _ => Ok(())
+            }
+        }
+    }
+}
+
+// How the request body is compressed, if it is ("identity" meaning it isn't).
+fn body_encoding(req: &Request) -> Option<String> {
+    req.headers.get_raw("Content-Encoding")
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).trim().to_lowercase())
+        .and_then(|encoding| if encoding.is_empty() || encoding == "identity" { None } else { Some(encoding) })
+}
+
+// The largest body any handler accepts, i.e. that of a batch of JSON pastes.
+fn max_body_bytes() -> u64 {
+    JSON_BODY_MAX_FACTOR * std::cmp::max(CONFIG.max_paste_bytes, CONFIG.batch_max_bytes) as u64
+}
+
+
+fn main() {
+    if std::env::args().skip(1).any(|arg| arg == "--hash-password") {
+        return print_password_hash();
+    }
+
+    // this has to happen before any other threads are started, so that they
+    // inherit the blocked signals
+    let shutdown = chan_signal::notify(&[Signal::INT, Signal::TERM]);
+
+    // load the configuration up front, so that any problems with it are reported now
+    println!("Configuration: {:?}", *CONFIG);
+    if HMAC_KEY.as_bytes().len() == 0 {
+        println!("You must set a key in hmac_key.txt");
+        std::process::exit(1);
+    }
+    // likewise any problems with custom themes and syntaxes
+    println!("Loaded {} highlighting themes", THEME_SET.themes.len());
+    for (ext, theme) in &CONFIG.language_themes {
+        if !THEME_SET.themes.contains_key(theme) {
+            println!("Unknown theme {} for .{} pastes, which will use {} instead", theme, ext, DEFAULT_THEME);
+        }
+    }
+    println!("Loaded {} custom syntaxes", CUSTOM_SYNTAXES.len());
+    // and start the highlighting threads loading their syntaxes, rather than
+    // leaving that to the first request for a highlighted paste
+    let _ = &*SYNTAX_JOBS;
+
+    let mut router = Routes::new();
+    router.get("/", usage, "index");
+    router.get("/help", help, "help");
+    router.get("/themes", themes, "themes");
+    router.get("/languages", languages, "languages");
+    router.get("/healthz", healthz, "healthz");
+    router.get("/metrics", metrics, "metrics");
+    router.get("/mine", mine, "mine");
+    router.get("/stats", stats, "stats");
+    router.get("/random", random_paste, "random");
+    router.get("/openapi.json", openapi_spec, "openapi");
+    router.get("/tags/:tag", tagged, "tagged");
+    router.get("/admin/pastes", admin_pastes, "admin_pastes");
+    router.delete("/admin/:paste_id", admin_delete, "admin_delete");
+    // namespaced pastes, which NamespaceMiddleware doesn't see behind /admin
+    router.delete("/admin/:namespace/:paste_id", admin_delete, "admin_delete_namespaced");
+    router.get("/:paste_id", retrieve, "retrieve");
+    router.get("/:paste_id/raw", retrieve_raw, "retrieve_raw");
+    router.get("/:paste_id/download", download, "download");
+    router.get("/:paste_id/qr", qr_code, "qr_code");
+    router.get("/:paste_id/info", info, "info");
+    router.get("/:paste_id/gist", gist, "gist");
+    router.get("/:paste_id/versions", versions, "versions");
+    router.get("/:paste_id/versions/:version", retrieve_version, "retrieve_version");
+    router.get("/:paste_id/diff/:from/:to", diff_versions, "diff");
+    router.get("/:paste_id/:lang", retrieve, "retrieve_lang");
+    router.get("/:paste_id/:key/delete", confirm_delete, "confirm_delete");
+    router.head("/:paste_id", retrieve, "head");
+    router.head("/:paste_id/raw", retrieve_raw, "head_raw");
+    router.head("/:paste_id/download", download, "head_download");
+    router.head("/:paste_id/:lang", retrieve, "head_lang");
+    router.delete("/:paste_id", delete, "delete_nokey");
+    router.delete("/:paste_id/:key", delete, "delete");
+    router.put("/:paste_id/:key", replace, "replace");
+    router.patch("/:paste_id/:key", append, "append");
+    router.post("/", submit, "submit");
+    router.post("/batch", batch, "batch");
+    router.post("/preview", preview, "preview");
+    // for browsers, which can't send DELETE from a plain form
+    router.post("/:paste_id/:key/delete", delete, "delete_form");
+    router.post("/:paste_id/:key/renew", renew_paste, "renew");
+    router.post("/:paste_id/:key/restore", restore_paste, "restore");
+    router.options("/", preflight, "preflight_root");
+    router.options("/:paste_id", preflight, "preflight");
+    router.options("/:paste_id/:key", preflight, "preflight_key");
+
+    let method_not_allowed = MethodNotAllowedMiddleware { globs: router.globs };
+    let mut mount = Mount::new();
+    // Mount sends each request to the longest of these which prefixes its path,
+    // so these don't get in the way of paste IDs, of which "static" can't be one.
+    mount.mount("/", router.router)
+         .mount("/webupload", Static::new(Path::new("./static/webupload.html")))
+         .mount("/static/", Static::new(Path::new("./static/")))
+         .mount("/favicon.ico", Static::new(Path::new("./static/favicon.ico")));
+
+    let hbse = templates();
+
+    *DISK_USAGE.lock().unwrap() = STORAGE.usage().expect("measuring paste storage") as usize;
+
+    let mut chain = Chain::new(mount);
+    chain.link_before(LoggingMiddleware);
+    chain.link_before(BasicAuthMiddleware);
+    chain.link_before(RateLimitMiddleware::new());
+    chain.link_before(BodyLimitMiddleware);
+    chain.link_before(persistent::Read::<bodyparser::MaxBodyLength>::one(max_body_bytes() as usize));
+    chain.link_before(NamespaceMiddleware);
+    chain.link_after(method_not_allowed);
+    chain.link_after(hbse);
+    chain.link_after(JsonErrorMiddleware);
+    chain.link_after(GzipMiddleware);
+    chain.link_after(HeadMiddleware);
+    chain.link_after(CorsMiddleware);
+    chain.link_after(LoggingMiddleware);
+    // Without a certificate we speak plain HTTP, presumably behind a proxy
+    // which terminates TLS and says so with X-Forwarded-Proto, see base_url().
+    let (mut server, scheme) = match (CONFIG.tls_cert.as_ref(), CONFIG.tls_key.as_ref()) {
+        (Some(cert), Some(key)) => {
+            let server = Iron::new(chain).https(CONFIG.socket.as_str(), PathBuf::from(cert), PathBuf::from(key));
+            (server.expect("starting the HTTPS server"), "https")
+        },
+        _ => (Iron::new(chain).http(CONFIG.socket.as_str()).unwrap(), "http")
+    };
+
+    println!("Listening on {}://{} ({})", scheme, CONFIG.socket, server.socket);
+
+    // every hour, delete pastes which have passed their expiry time
+    let (stop_cleanup, cleanup_stopped) = mpsc::channel::<()>();
+    // Failures are logged rather than allowed to panic, which would stop any
+    // further cleanup until the server is restarted.
+    let cleanup = thread::spawn(move || {
+        let interval = time::Duration::from_secs(CONFIG.cleanup_interval_secs);
+        println!("Pastes are deleted after at most {} days, checking every {} seconds.",
+                 CONFIG.paste_days, CONFIG.cleanup_interval_secs);
+        loop {
+            {
+                let _writing = WRITES.read().unwrap();
+                let now = UTC::now().timestamp();
+                match STORAGE.delete_expired(now) {
+                    Ok(results) => for (id, result) in results {
+                        match result {
+                            Ok(modified) => println!("Deleted expired paste {} (last modified {:.1} days ago)",
+                                                     id, (now - modified) as f64 / DAY_SECS as f64),
+                            Err(e) => println!("Could not delete expired paste {}: {}", id, e)
+                        }
+                    },
+                    Err(e) => println!("Could not look for expired pastes: {}", e)
+                }
+                if let Err(e) = STORAGE.prune_tombstones(now - CONFIG.tombstone_days as i64 * DAY_SECS) {
+                    println!("Could not prune tombstones: {}", e);
+                }
+                match STORAGE.purge_trash(now - CONFIG.trash_days as i64 * DAY_SECS) {
+                    Ok(ids) => for id in ids {
+                        println!("Deleted trashed paste {} for good", id);
+                    },
+                    Err(e) => println!("Could not empty the trash: {}", e)
+                }
+                match STORAGE.usage() {
+                    Ok(usage) => *DISK_USAGE.lock().unwrap() = usage as usize,
+                    Err(e) => println!("Could not measure paste storage: {}", e)
+                }
+            }
+            // sleeps until the next check, unless the server shuts down in the meantime
+            match cleanup_stopped.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                _ => break
+            }
+        }
+    });
+
+    // Stop accepting connections, then wait for any writes which are underway
+    // (and keep new ones from starting) before exiting.
+    let signal = shutdown.recv();
+    println!("Received {:?}, shutting down.", signal);
+    if let Err(e) = server.close() {
+        println!("Error closing the listening socket: {}", e);
+    }
+    let _writes = WRITES.write().unwrap();
+    let _ = stop_cleanup.send(());
+    cleanup.join().expect("joining the cleanup thread");
+    println!("Shut down cleanly.");
+}
+
+
+fn usage(req: &mut Request) -> IronResult<Response> {
+    render_info_page(req, "index")
+}
+
+fn help(req: &mut Request) -> IronResult<Response> {
+    render_info_page(req, "help")
+}
+
+// Both pages are rendered from the same data, so that they can't drift apart.
+fn render_info_page(req: &Request, template: &str) -> IronResult<Response> {
+    Ok(info_page(template, info_page_data(base_url(req))))
+}
+
+fn info_page(template: &str, data: BTreeMap<String, String>) -> Response {
+    let mut resp = Response::new();
+    resp.set_mut(Header(ContentType::plaintext()));
+    resp.set_mut(Template::new(template, data)).set_mut(status::Ok);
+    resp
+}
+
+// What the usage and help pages fill in: examples, and the configured limits.
+fn info_page_data(base_url: String) -> BTreeMap<String, String> {
+    let mut data = BTreeMap::new();
+    data.insert("base_url".to_string(), base_url);
+    data.insert("id".to_string(), CONFIG.example_id.clone());
+    data.insert("key".to_string(), CONFIG.example_key.clone());
+    data.insert("ext".to_string(), CONFIG.example_ext.clone());
+    if let Some(ref banner) = CONFIG.banner {
+        data.insert("banner".to_string(), banner.clone());
+    }
+    data.insert("days".to_string(), CONFIG.paste_days.to_string());
+    data.insert("versions".to_string(), CONFIG.versions.to_string());
+    data.insert("max_mb".to_string(), CONFIG.max_paste_mb().to_string());
+    data.insert("batch_max_mb".to_string(), (CONFIG.batch_max_bytes as f64 / 1048576.0).to_string());
+    data.insert("max_renewals".to_string(), CONFIG.max_renewals.to_string());
+    if CONFIG.dedup {
+        data.insert("dedup".to_string(), "true".to_string());
+    }
+    if CONFIG.max_pastes_per_ip > 0 {
+        data.insert("max_pastes_per_ip".to_string(), CONFIG.max_pastes_per_ip.to_string());
+    }
+    if CONFIG.trash_days > 0 {
+        data.insert("trash_days".to_string(), CONFIG.trash_days.to_string());
+    }
+    data
+}
+
+// Note: webform is multipart/form-data, which is how we tell it apart from a
+// raw post. Doing so allows us to unambiguously differentiate between a "data"
+// variable (from the web form) and a raw post that happens contain
+// urlencoded query params. Is this poor style?
+fn submit(req: &mut Request) -> IronResult<Response> {
+    // get paste contents, either raw post or data param.
+    // Forms may carry the paste either as a "data" field or as an uploaded
+    // "file" part (e.g. curl -F file=@main.rs), but not both at once.
+    let (paste, source, uploaded_filename) = if is_json(req) {
+        if declared_over(req, JSON_BODY_MAX_FACTOR * CONFIG.max_paste_bytes as u64) {
+            return Ok(Response::with(too_large()));
+        }
+        let body = try!(read_limited(req, JSON_BODY_MAX_FACTOR * CONFIG.max_paste_bytes as u64).map_err(body_error));
+        if str::from_utf8(&body).is_err() {
+            return Ok(Response::with(not_utf8()));
+        }
+        match parse_json_submission(&body) {
+            Ok((content, fields)) => (content.into_bytes(), Source::Json(fields), None),
+            Err(errors) => {
+                let mut body = BTreeMap::new();
+                body.insert("errors".to_string(), errors.to_json());
+                let mut resp = Response::with((status::UnprocessableEntity, Json::Object(body).to_string()));
+                resp.set_mut(Header(ContentType::json()));
+                return Ok(resp);
+            }
+        }
+    } else if !is_multipart(req) {
+        if declared_over(req, CONFIG.max_paste_bytes as u64) {
+            return Ok(Response::with(too_large()));
+        }
+        (try!(read_body(req).map_err(body_error)), Source::Query, None)
+    } else {
+        let params = req.get_ref::<Params>().unwrap();
+        match (params.find(&["data"]), params.find(&["file"])) {
+            (Some(&Value::String(ref data)), None) => (data.clone().into_bytes(), Source::Form, None),
+            (None, Some(&Value::File(ref file))) => {
+                // checked before reading it, since it could be anything
+                if file.size > CONFIG.max_paste_bytes as u64 {
+                    return Ok(Response::with(too_large()))
+                }
+                let mut contents = Vec::new();
+                itry!(File::open(&file.path).and_then(|mut f| f.read_to_end(&mut contents)));
+                (contents, Source::Form, file.filename.clone())
+            },
+            (Some(_), Some(_)) => return Ok(Response::with((status::BadRequest, "Submit either \"data\" or \"file\", not both.\n"))),
+            _ => return Ok(Response::with((status::BadRequest, "No paste data submitted.\n")))
+        }
+    };
+    match store_paste(req, paste, &source, uploaded_filename, None) {
+        Ok(submission) => Ok(submitted(req, &submission)),
+        Err((status, message)) => Ok(Response::with((status, message)))
+    }
+}
+
+// Creates each of a JSON array of pastes, as though they had been submitted one
+// at a time as JSON, and reports on each separately, so that one bad paste
+// doesn't keep the rest from being created.
+fn batch(req: &mut Request) -> IronResult<Response> {
+    if declared_over(req, JSON_BODY_MAX_FACTOR * CONFIG.batch_max_bytes as u64) {
+        return Ok(Response::with((status::PayloadTooLarge, format!(
+            "Batches may not be more than {} MB in total.\n", CONFIG.batch_max_bytes as f64 / 1048576.0))));
+    }
+    let body = try!(read_limited(req, JSON_BODY_MAX_FACTOR * CONFIG.batch_max_bytes as u64).map_err(body_error));
+    if str::from_utf8(&body).is_err() {
+        return Ok(Response::with(not_utf8()));
+    }
+    let pastes = match str::from_utf8(&body).ok().and_then(|body| Json::from_str(body).ok()) {
+        Some(Json::Array(pastes)) => pastes,
+        _ => return Ok(Response::with((status::BadRequest, "Invalid request: expected a JSON array of pastes.\n")))
+    };
+    if pastes.len() > BATCH_MAX_PASTES {
+        return Ok(Response::with((status::BadRequest, format!("Batches may not hold more than {} pastes.\n", BATCH_MAX_PASTES))));
+    }
+    let total: usize = pastes.iter()
+        .filter_map(|paste| paste.find("content").and_then(|content| content.as_string()))
+        .map(|content| content.len())
+        .sum();
+    if total > CONFIG.batch_max_bytes {
+        return Ok(Response::with((status::BadRequest, format!(
+            "Batches may not be more than {} MB in total.\n", CONFIG.batch_max_bytes as f64 / 1048576.0))));
+    }
+
+    let mut results = Vec::new();
+    for paste in pastes {
+        let mut result = BTreeMap::new();
+        match check_json_submission(Some(paste)) {
+            Ok((content, fields)) => match store_paste(req, content.into_bytes(), &Source::Json(fields), None, None) {
+                Ok(submission) => {
+                    let (view_url, _) = paste_urls(req, &submission.id, &submission.key);
+                    result.insert("id".to_string(), submission.id.to_json());
+                    result.insert("url".to_string(), view_url.to_json());
+                    result.insert("key".to_string(), submission.key.to_json());
+                },
+                Err((status, message)) => {
+                    result.insert("status".to_string(), status.to_u16().to_json());
+                    result.insert("error".to_string(), message.trim_right().to_json());
+                }
+            },
+            Err(errors) => {
+                result.insert("status".to_string(), status::UnprocessableEntity.to_u16().to_json());
+                result.insert("errors".to_string(), errors.to_json());
+            }
+        }
+        results.push(Json::Object(result));
+    }
+    let mut resp = Response::with((status::Ok, Json::Array(results).to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    Ok(resp)
+}
+
+// A paste which has just been stored.
+struct Submission {
+    id: String,
+    key: String,
+    ttl: i64,
+    burn: bool,
+    size: usize
+}
+
+// Stores a submitted paste according to the options accompanying it, or says
+// why it couldn't be (with the status to respond with). The paste is stored at
+// the ID `at` if given (see upsert()), rather than any "namespace" and
+// "custom_id" asked for.
+fn store_paste(req: &mut Request, paste: Vec<u8>, source: &Source, uploaded_filename: Option<String>, at: Option<&str>)
+    -> Result<Submission, (status::Status, String)>
+{
+    let ttl = match submit_param(req, "expires", source) {
+        Some(expires) => match parse_ttl(&expires) {
+            Ok(ttl) => ttl,
+            Err(reason) => return Err((status::BadRequest, format!("Invalid expiry: {}.\n", reason)))
+        },
+        None => CONFIG.max_ttl()
+    };
+    let burn = submit_param(req, "burn", source).map_or(false, |burn| burn == "true");
+    let password = submit_param(req, "password", source);
+    let (namespace, custom_id) = match at {
+        Some(id) => match id.find('/') {
+            Some(i) => (Some(id[..i].to_string()), Some(id[i + 1..].to_string())),
+            None => (None, Some(id.to_string()))
+        },
+        None => (submit_param(req, "namespace", source), submit_param(req, "custom_id", source))
+    };
+    // an explicit filename wins over that of an uploaded file
+    let filename = submit_param(req, "filename", source).or_else(|| request_filename(req)).or(uploaded_filename)
+        .and_then(|filename| clean_filename(&filename));
+    let title = submit_param(req, "title", source).and_then(|title| clean_title(&title));
+    let tags = match submit_param(req, "tags", source).map(|tags| parse_tags(&tags)) {
+        Some(Ok(tags)) => tags,
+        Some(Err(reason)) => return Err((status::BadRequest, format!("Invalid tags: {}.\n", reason))),
+        None => Vec::new()
+    };
+    let language = submit_param(req, "language", source);
+    if let Some(ref language) = language {
+        if !known_language(language) {
+            return Err((status::BadRequest, format!("Invalid language: \"{}\" is not a language we can highlight.\n", language)));
+        }
+    }
+    let owner = match request_token(req) {
+        Ok(token) => token.map(|token| token_hash(&token)),
+        Err(reason) => return Err((status::BadRequest, format!("Invalid API token: {}.\n", reason)))
+    };
+    if let Some(ref namespace) = namespace {
+        if let Err(reason) = validate_namespace(namespace) {
+            return Err((status::BadRequest, format!("Invalid namespace: {}.\n", reason)));
+        }
+        if STORAGE.exists(namespace) {
+            return Err((status::Conflict, format!("Namespace {} is already taken by a paste.\n", namespace)));
+        }
+    }
+    if paste.is_empty() {
+        return Err((status::BadRequest, "No paste data submitted.\n".to_string()))
+    }
+    // verify max size before saving it
+    try!(check_paste_size(&paste));
+    // there's no telling how much space the paste will take up until it has
+    // been stored, so this only turns it away if there's none at all
+    if disk_full() {
+        return Err(server_full())
+    }
+    let submitter = submitter_hash(&req.remote_addr.ip().to_string());
+    let _capped = try!(check_paste_cap(CONFIG.max_pastes_per_ip,
+                                       || STORAGE.count_submitted(&submitter, UTC::now().timestamp())));
+    let _writing = WRITES.read().unwrap();
+    // Only pastes which nothing sets apart from one another are shared. Nor
+    // are those belonging to a token, which would then list them as its own,
+    // or those in a namespace, which would then be in somebody else's, or
+    // those with tags, which would then be listed under somebody else's.
+    let hash = if CONFIG.dedup && !burn && password.is_none() && custom_id.is_none()
+        && owner.is_none() && namespace.is_none() && tags.is_empty() {
+        Some(content_hash(&paste))
+    } else {
+        None
+    };
+    if let Some(ref hash) = hash {
+        if let Some((id, ttl, key)) = try!(deduplicate(hash, ttl, &filename, &title, &language).map_err(storage_error)) {
+            PASTES_CREATED.fetch_add(1, Ordering::Relaxed);
+            return Ok(Submission { id: id, key: key, ttl: ttl, burn: false, size: paste.len() });
+        }
+    }
+    // get paste ID and URL
+    let prefix = namespace.map_or(String::new(), |namespace| format!("{}/", namespace));
+    // top-level IDs mustn't be taken by namespaces either
+    let create = |id: &str| {
+        if try!(STORAGE.is_namespace(id)) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is a namespace", id)));
+        }
+        STORAGE.create(id, &paste)
+    };
+    let id = match custom_id {
+        Some(custom_id) => {
+            if let Err(reason) = validate_custom_id(&custom_id) {
+                return Err((status::BadRequest, format!("Invalid custom ID: {}.\n", reason)));
+            }
+            let id = format!("{}{}", prefix, custom_id);
+            match create(&id) {
+                Ok(()) => id,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    return Err((status::Conflict, format!("Paste {} already exists.\n", id)));
+                },
+                Err(e) => return Err(storage_error(e))
+            }
+        },
+        None => try!(allocate_id(CONFIG.id_len, |len| format!("{}{}", prefix, generate_id(len, CONFIG.id_alphabet.as_bytes())), create)
+                     .map_err(storage_error))
+    };
+    if !charge_disk_usage(try!(STORAGE.size(&id).map_err(storage_error)) as usize, 0) {
+        try!(STORAGE.delete(&id).map_err(storage_error));
+        return Err(server_full());
+    }
+    let mut meta = PasteMeta::new(ttl);
+    meta.burn = burn;
+    meta.password = password.map(|password| password_hash(&id, &password));
+    meta.filename = filename;
+    meta.title = title;
+    meta.language = language;
+    meta.tags = tags;
+    // a random key can't be worked out again, so this is the only time it is seen
+    let key = if CONFIG.random_keys {
+        let key = random_key();
+        meta.key_hash = Some(key_hash(&key, &random_key()));
+        key
+    } else {
+        gen_key(&id)
+    };
+    meta.hash = hash;
+    meta.owner = owner;
+    meta.submitter = Some(submitter);
+    try!(STORAGE.save_meta(&id, &meta).map_err(storage_error));
+    PASTES_CREATED.fetch_add(1, Ordering::Relaxed);
+    Ok(Submission { id: id, key: key, ttl: ttl, burn: burn, size: paste.len() })
+}
+
+// Refuses a submission from somebody who already has `max` live pastes, as
+// `count` says. Otherwise yields a lock to hold until the new paste has been
+// stored, without which concurrent submissions could all be counted before any
+// of them had been stored, and so all get in under the cap.
+fn check_paste_cap<C>(max: u32, count: C) -> Result<Option<MutexGuard<'static, ()>>, (status::Status, String)>
+    where C: FnOnce() -> io::Result<u64>
+{
+    if max == 0 {
+        return Ok(None);
+    }
+    let lock = SUBMITTER_LOCK.lock().unwrap();
+    if try!(count().map_err(storage_error)) >= max as u64 {
+        return Err((status::Forbidden, format!("You may not have more than {} pastes at once; \
+                                                delete some, or wait for them to expire.\n", max)));
+    }
+    Ok(Some(lock))
+}
+
+// For failures which are no fault of the submitter's.
+fn storage_error(e: io::Error) -> (status::Status, String) {
+    (status::InternalServerError, format!("Could not store the paste: {}.\n", e))
+}
+
+// The response to a successful submission, whose Location is the new paste.
+fn submitted(req: &Request, submission: &Submission) -> Response {
+    let burn_notice = if submission.burn { ", or as soon as it is viewed" } else { "" };
+    let (view_url, edit_url) = paste_urls(req, &submission.id, &submission.key);
+    let mut resp = if wants_json(req) {
+        let mut info = BTreeMap::new();
+        info.insert("id".to_string(), submission.id.to_json());
+        info.insert("key".to_string(), submission.key.to_json());
+        info.insert("view_url".to_string(), view_url.to_json());
+        info.insert("edit_url".to_string(), edit_url.to_json());
+        info.insert("delete_url".to_string(), format!("{}/delete", edit_url).to_json());
+        info.insert("size".to_string(), submission.size.to_json());
+        let mut resp = Response::with((status::Created, Json::Object(info).to_string()));
+        resp.set_mut(Header(ContentType::json()));
+        resp
+    } else {
+        Response::with((status::Created, format!(
+            "View URL: {view_url}\nEdit URL: {edit_url}\nDelete URL: {edit_url}/delete\n\nThis paste will be deleted in {ttl}{burn}.\n",
+            view_url = view_url, edit_url = edit_url, ttl = describe_ttl(submission.ttl), burn = burn_notice)))
+    };
+    resp.headers.set(Location(view_url));
+    resp
+}
+
+// Rather than storing another copy of an existing paste, hands out the existing
+// one to whoever submits it again, provided that its metadata matches too. Each
+// of them gets an edit key of their own, which can only delete their own share
+// of it (see release_reference()). Yields the paste's ID, its (possibly
+// extended) lifetime, which is the longest that any of its submitters asked
+// for, and the new key.
+fn deduplicate(hash: &str, ttl: i64, filename: &Option<String>, title: &Option<String>, language: &Option<String>)
+    -> io::Result<Option<(String, i64, String)>>
+{
+    let _lock = META_LOCK.lock().unwrap();
+    let id = match try!(STORAGE.find_by_hash(hash)) {
+        Some(id) => id,
+        None => return Ok(None)
+    };
+    let mut meta = match STORAGE.load_meta(&id) {
+        Some(meta) => meta,
+        None => return Ok(None)
+    };
+    if meta.is_expired() || meta.burn || meta.password.is_some() || meta.owner.is_some()
+        || meta.filename != *filename || meta.title != *title || meta.language != *language {
+        return Ok(None);
+    }
+    let key = random_key();
+    meta.ref_keys.push(key_hash(&key, &random_key()));
+    meta.refs += 1;
+    meta.ttl = std::cmp::max(meta.ttl, ttl);
+    meta.expires = std::cmp::max(meta.expires, UTC::now().timestamp() + ttl);
+    try!(STORAGE.save_meta(&id, &meta));
+    Ok(Some((id, meta.ttl, key)))
+}
+
+// CORS preflight requests; CorsMiddleware adds the actual headers.
+fn preflight(_: &mut Request) -> IronResult<Response> {
+    Ok(Response::with(status::NoContent))
+}
+
+fn retrieve(req: &mut Request) -> IronResult<Response> {
+    retrieve_paste(req, false)
+}
+
+// In raw mode, the paste is served exactly as it was submitted.
+fn retrieve_paste(req: &mut Request, raw: bool) -> IronResult<Response> {
+    let (id, lang) = {
+        let params = req.extensions.get::<Router>().unwrap();
+        (paste_id(req), params.find("lang").map(|lang| lang.to_string()))
+    };
+    if let Err(reason) = validate_paste_id(&id) {
+        return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))));
+    }
+    // HEAD requests neither burn nor count as a view of the paste
+    let head = req.method == Method::Head;
+
+    let meta = STORAGE.load_meta(&id);
+    // expired pastes may not have been swept up by the cleanup thread yet
+    if meta.as_ref().map_or(false, |meta| meta.is_expired()) {
+        return Ok(gone_paste(&id, "expired"));
+    }
+    // describing a paste neither requires its password nor burns it
+    if !raw && wants_json(req) {
+        let (info, etag) = match paste_info(&id, meta.as_ref()) {
+            Ok(info) => info,
+            Err(_) => return Ok(missing_paste(&id))
+        };
+        if etag_matches(req, &etag) {
+            return Ok(Response::with((status::NotModified, Header(ETag(etag)))));
+        }
+        let mut resp = Response::with((status::Ok, info.to_string()));
+        resp.set_mut(Header(ContentType::json())).set_mut(Header(ETag(etag)));
+        if let Ok(times) = paste_times(&id, meta.as_ref()) {
+            set_timestamps(&mut resp, times);
+        }
+        return Ok(resp);
+    }
+    if !password_valid(req, &id, meta.as_ref()) {
+        return Ok(Response::with((status::Unauthorized, format!("Paste {} requires a valid password\n", id))));
+    }
+    let burn = !head && meta.as_ref().map_or(false, |meta| meta.burn);
+    // Raw pastes are streamed rather than read into memory all at once, apart
+    // from burnt ones, which have to be taken out of storage in one go.
+    if raw && !burn {
+        return stream_paste(req, &id);
+    }
+    // without an explicit lang, use the paste's default if asked to
+    let lang = if raw {
+        None
+    } else if lang.is_some() {
+        lang
+    } else if highlight_by_default(req) {
+        meta.as_ref().and_then(default_language)
+    } else {
+        None
+    };
+
+    // measured up front, since a burnt paste is gone once it has been read
+    let stored_len = STORAGE.size(&id).unwrap_or(0) as usize;
+    let times = paste_times(&id, meta.as_ref()).ok();
+    // burning a paste writes to storage, from taking it until it is either
+    // buried or put back
+    let _writing = if burn { Some(WRITES.read().unwrap()) } else { None };
+    let paste = if burn { itry!(STORAGE.take(&id)) } else { itry!(STORAGE.load(&id)) };
+    let paste = match paste {
+        Some(paste) => paste,
+        None => return Ok(missing_paste(&id))
+    };
+    // in case highlighting fails and the paste has to be put back
+    let unburnt = if burn { Some(paste.clone()) } else { None };
+
+    // Every view of a paste shares the same ETag, so that it only changes when
+    // the paste does. A burn-after-reading paste has to be served regardless.
+    let etag = content_etag(&paste);
+    let unmodified = times.map_or(false, |(modified, _)| unmodified_since(req, modified));
+    if !burn && (etag_matches(req, &etag) || unmodified) {
+        return Ok(Response::with((status::NotModified, Header(ETag(etag)))));
+    }
+    // HEAD requests get the whole response too, so that HeadMiddleware can
+    // give its length, which is that of the paste as highlighted or rendered
+    let title = meta.as_ref().and_then(|meta| meta.title.clone());
+    let (mut resp, detected) = match String::from_utf8(paste) {
+        Ok(buffer) => render_paste(req, &id, buffer, lang, title),
+        // binary pastes are served as they are, since there's nothing to highlight
+        Err(e) => {
+            let mut resp = Response::with((status::Ok, e.into_bytes()));
+            resp.set_mut(Header(ContentType(Mime(TopLevel::Application, SubLevel::OctetStream, vec![]))));
+            (resp, None)
+        }
+    };
+
+    if let Some(name) = detected {
+        resp.headers.set_raw("X-Detected-Language", vec![name.into_bytes()]);
+    }
+    if resp.status == Some(status::Ok) {
+        resp.headers.set(ETag(etag));
+        if let Some(times) = times {
+            set_timestamps(&mut resp, times);
+        }
+        if !head {
+            itry!(count_view(&id, burn, &mut resp));
+        }
+    }
+
+    if burn {
+        if resp.status != Some(status::Ok) {
+            // nobody got to see the paste (e.g. it couldn't be highlighted or
+            // rendered as an image), so put it back
+            if let (Some(paste), Some(meta)) = (unburnt, meta) {
+                itry!(STORAGE.save(&id, &paste));
+                itry!(STORAGE.save_meta(&id, &meta));
+            }
+        } else {
+            adjust_disk_usage(0, stored_len);
+            itry!(STORAGE.bury(&id, "burnt"));
+            resp.headers.set_raw("X-Burn-After-Reading", vec![b"true".to_vec()]);
+        }
+    }
+    Ok(resp)
+}
+
+fn stream_paste(req: &mut Request, id: &str) -> IronResult<Response> {
+    let (etag, len, text) = match itry!(STORAGE.open(id)) {
+        Some(mut paste) => itry!(scan_paste(&mut paste)),
+        None => return Ok(missing_paste(id))
+    };
+    let times = itry!(paste_times(id, STORAGE.load_meta(id).as_ref()));
+    if etag_matches(req, &etag) || unmodified_since(req, times.0) {
+        return Ok(Response::with((status::NotModified, Header(ETag(etag)))));
+    }
+    if req.method == Method::Head {
+        let mut resp = Response::with(status::Ok);
+        resp.headers.set(ContentLength(len));
+        resp.headers.set(ETag(etag));
+        resp.headers.set(AcceptRanges(vec![RangeUnit::Bytes]));
+        set_timestamps(&mut resp, times);
+        return Ok(resp);
+    }
+    // Only a single range is supported, since several would have to be sent
+    // as multipart/byteranges. Range headers in other units are ignored.
+    let range = match req.headers.get::<Range>() {
+        Some(&Range::Bytes(ref specs)) => {
+            match (specs.len(), specs.first().and_then(|spec| byte_range(spec, len))) {
+                (1, Some(range)) => Some(range),
+                _ => {
+                    let mut resp = Response::with((status::RangeNotSatisfiable, "Requested range not satisfiable\n"));
+                    resp.headers.set(ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(len) }));
+                    return Ok(resp);
+                }
+            }
+        },
+        _ => None
+    };
+    // Should the paste be replaced in the meantime, the ETag describes the
+    // previous version, which only costs the client an extra download later.
+    let mut paste = match itry!(STORAGE.open(id)) {
+        Some(paste) => paste,
+        None => return Ok(missing_paste(id))
+    };
+    let mut resp = match range {
+        Some((first, last)) => {
+            // pastes are stored compressed, so there's no seeking to the start
+            itry!(io::copy(&mut paste.by_ref().take(first), &mut io::sink()));
+            let mut resp = Response::with(status::PartialContent);
+            resp.body = Some(Box::new(BodyReader(paste.take(last - first + 1))));
+            resp.headers.set(ContentLength(last - first + 1));
+            resp.headers.set(ContentRange(ContentRangeSpec::Bytes { range: Some((first, last)), instance_length: Some(len) }));
+            resp
+        },
+        None => {
+            let mut resp = Response::with(status::Ok);
+            resp.body = Some(Box::new(BodyReader(paste)));
+            resp
+        }
+    };
+    if !text {
+        resp.set_mut(Header(ContentType(Mime(TopLevel::Application, SubLevel::OctetStream, vec![]))));
+    }
+    resp.headers.set(ETag(etag));
+    resp.headers.set(AcceptRanges(vec![RangeUnit::Bytes]));
+    set_timestamps(&mut resp, times);
+    // resuming a download doesn't count as another view
+    if range.is_none() {
+        itry!(count_view(id, false, &mut resp));
+    }
+    Ok(resp)
+}
+
+// The first and last byte (inclusive) of a paste of `len` bytes that `spec`
+// asks for, if it asks for any at all.
+fn byte_range(spec: &ByteRangeSpec, len: u64) -> Option<(u64, u64)> {
+    match *spec {
+        ByteRangeSpec::FromTo(first, last) if first < len && first <= last => Some((first, std::cmp::min(last, len - 1))),
+        ByteRangeSpec::AllFrom(first) if first < len => Some((first, len - 1)),
+        ByteRangeSpec::Last(n) if n > 0 && len > 0 => Some((len.saturating_sub(n), len - 1)),
+        _ => None
+    }
+}
+
+// Hashes a paste for its ETag (matching content_etag()), measures it and checks
+// whether it is UTF-8 text, a bit at a time.
+fn scan_paste(paste: &mut Read) -> io::Result<(EntityTag, u64, bool)> {
+    let mut hasher = Sha256::new();
+    let mut len = 0;
+    let mut text = true;
+    let mut buf = [0; 8192];
+    let mut pending = Vec::new(); // not yet known to be valid UTF-8
+    loop {
+        let n = try!(paste.read(&mut buf));
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+        len += n as u64;
+        if text {
+            pending.extend_from_slice(&buf[..n]);
+            let valid = match str::from_utf8(&pending) {
+                Ok(_) => pending.len(),
+                Err(e) => e.valid_up_to()
+            };
+            // anything more than the start of a character cut off by the end
+            // of the buffer can't be made good by the next read
+            if pending.len() - valid > 3 {
+                text = false;
+            }
+            pending.drain(..valid);
+        }
+    }
+    Ok((EntityTag::new(false, hasher.result_str()), len, text && pending.is_empty()))
+}
+
+// Counts a successful retrieval, and tells the client how many views it makes.
+fn count_view(id: &str, burn: bool, resp: &mut Response) -> io::Result<()> {
+    PASTES_RETRIEVED.fetch_add(1, Ordering::Relaxed);
+    // a burnt paste's metadata is about to go, and it can only be viewed once anyway
+    let views = if burn { Some(1) } else { try!(record_view(id)) };
+    if let Some(views) = views {
+        resp.headers.set_raw("X-View-Count", vec![views.to_string().into_bytes()]);
+    }
+    Ok(())
+}
+
+// Renders a text paste as requested by lang, also returning the name of the
+// language if it had to be detected.
+fn render_paste(req: &mut Request, id: &str, buffer: String, lang: Option<String>, title: Option<String>)
+    -> (Response, Option<String>)
+{
+    // "auto" picks the syntax from the first line (e.g. a shebang), and falls
+    // back to no highlighting at all if nothing matches
+    let detected = match lang {
+        Some(ref lang) if lang == "auto" => detect_language(&buffer),
+        _ => None
+    };
+    let lang = match lang {
+        Some(ref lang) if lang == "auto" => detected.as_ref().map(|&(_, ref ext)| ext.clone()),
+        _ => lang
+    };
+
+    let resp = match lang {
+        // markdown is rendered for browsers, and left as it is for everyone else
+        Some(ref lang) if lang == "md" && is_curl(req) => Response::with((status::Ok, buffer)),
+        Some(ref lang) if lang.ends_with(".png") => {
+            let lang = &lang[..lang.len() - ".png".len()];
+            let (theme, _) = find_theme(query_param(req, "theme"), lang);
+            png_response(&buffer, lang, theme)
+        },
+        Some(ref lang) if lang == "md" => {
+            let mut resp = Response::new();
+            let mut data = page_data(id, title.as_ref());
+            data.insert("markdown".to_string(), render_markdown(&buffer).to_json());
+            resp.set_mut(Template::new("paste_html", data)).set_mut(status::Ok);
+            resp
+        },
+        Some(lang) => {
+            // highlighting pathologically large pastes would take too long
+            if let Some(reason) = too_large_to_highlight(&buffer) {
+                let mut resp = Response::with((status::Ok, buffer));
+                resp.headers.set_raw("X-Highlighting-Skipped", vec![reason.into_bytes()]);
+                return (resp, detected.map(|(name, _)| name));
+            }
+            // syntax highlighting
+            let html_output = !is_curl(req);
+            // e.g. for scripts saving the terminal view, which don't want the escapes
+            if !html_output && !wants_color(req) {
+                let resp = match check_language(&lang) {
+                    Ok(()) => Response::with((status::Ok, buffer)),
+                    Err(s) => Response::with((status::BadRequest, format!("Invalid request: {}.\n", s)))
+                };
+                return (resp, detected.map(|(name, _)| name));
+            }
+            let (theme, theme_found) = find_theme(query_param(req, "theme"), &lang);
+            let mut resp = match highlight(buffer, &lang, html_output, theme) {
+                HighlightedText::Terminal(s) => Response::with((status::Ok, s)),
+                HighlightedText::Html(lines) => {
+                    let numbers = query_param(req, "nums").map_or(true, |nums| nums != "false");
+                    // long lines scroll sideways unless asked to wrap
+                    let wrap = query_param(req, "wrap").map_or(false, |wrap| wrap == "true");
+                    let (first, last) = query_param(req, "lines")
+                        .and_then(|range| parse_line_range(&range, lines.len()))
+                        .unwrap_or((0, 0));
+                    let lines: Vec<HtmlLine> = lines.into_iter().enumerate()
+                        .map(|(i, html)| HtmlLine {
+                            number: i + 1,
+                            html: html,
+                            selected: first <= i + 1 && i + 1 <= last
+                        })
+                        .collect();
+                    let mut resp = Response::new();
+                    let mut data = page_data(id, title.as_ref());
+                    data.insert("lines".to_string(), lines.to_json());
+                    data.insert("numbers".to_string(), numbers.to_json());
+                    data.insert("wrap".to_string(), wrap.to_json());
+                    data.insert("background".to_string(), theme_background(theme).to_json());
+                    resp.set_mut(Template::new("paste_html", data)).set_mut(status::Ok);
+                    resp
+                },
+                HighlightedText::Error(s) => Response::with((status::BadRequest, format!("Invalid request: {}.\n", s)))
+            };
+            if !theme_found {
+                let warning = format!("299 - \"Unknown theme, using {}\"", DEFAULT_THEME);
+                resp.headers.set_raw("Warning", vec![warning.into_bytes()]);
+            }
+            resp
+        },
+        // no syntax highlighting
+        None => {
+            Response::with((status::Ok, buffer))
+        }
+    };
+    (resp, detected.map(|(name, _)| name))
+}
+
+// Highlights the body as HTML without storing it, e.g. for showing what a paste
+// will look like while it is being written. The body is either raw, with the
+// language and theme as query parameters, or a JSON object holding "content",
+// "language" and "theme". Yields just the highlighted <pre> element.
+fn preview(req: &mut Request) -> IronResult<Response> {
+    let json = is_json(req);
+    let limit = if json { JSON_BODY_MAX_FACTOR * CONFIG.max_paste_bytes as u64 } else { CONFIG.max_paste_bytes as u64 };
+    if declared_over(req, limit) {
+        return Ok(Response::with(too_large()));
+    }
+    let body = try!(read_limited(req, limit).map_err(body_error));
+    let (content, lang, theme) = if json {
+        let fields = match str::from_utf8(&body).ok().and_then(|body| Json::from_str(body).ok()) {
+            Some(Json::Object(fields)) => fields,
+            _ => return Ok(Response::with((status::BadRequest, "Invalid request: expected a JSON object.\n")))
+        };
+        let field = |name: &str| fields.get(name).and_then(|value| value.as_string()).map(|value| value.to_string());
+        (field("content"), field("language"), field("theme"))
+    } else {
+        (String::from_utf8(body).ok(), query_param(req, "lang"), query_param(req, "theme"))
+    };
+    let content = match content {
+        Some(content) => content,
+        None => return Ok(Response::with((status::BadRequest, "Invalid request: the content must be UTF-8 text.\n")))
+    };
+    let lang = match lang {
+        Some(lang) => lang,
+        None => return Ok(Response::with((status::BadRequest, "Invalid request: no language given.\n")))
+    };
+    if content.len() > CONFIG.max_paste_bytes {
+        return Ok(Response::with(too_large()));
+    }
+    if let Some(reason) = too_large_to_highlight(&content) {
+        return Ok(Response::with((status::BadRequest, format!("Invalid request: too large to highlight ({}).\n", reason))));
+    }
+    let (theme, _) = find_theme(theme, &lang);
+    let lines = match highlight(content, &lang, true, theme) {
+        HighlightedText::Html(lines) => lines,
+        HighlightedText::Error(s) => return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", s)))),
+        HighlightedText::Terminal(_) => unreachable!()
+    };
+    let html = format!("<pre style=\"background-color: {}\">{}</pre>\n", theme_background(theme), lines.join("\n"));
+    let mut resp = Response::with((status::Ok, html));
+    resp.set_mut(Header(ContentType::html()));
+    Ok(resp)
+}
+
+// Says why a paste can't be highlighted, if it exceeds the configured limits.
+fn too_large_to_highlight(buffer: &str) -> Option<String> {
+    if CONFIG.highlight_max_lines > 0 && buffer.lines().count() > CONFIG.highlight_max_lines {
+        return Some(format!("more than {} lines", CONFIG.highlight_max_lines));
+    }
+    if CONFIG.highlight_max_line_len > 0 && buffer.lines().any(|line| line.chars().count() > CONFIG.highlight_max_line_len) {
+        return Some(format!("lines longer than {} characters", CONFIG.highlight_max_line_len));
+    }
+    None
+}
+
+// Renders the paste as an image of its highlighted source.
+fn png_response(buffer: &str, lang: &str, theme: &Theme) -> Response {
+    let font = match *PNG_FONT {
+        Some(ref font) => font,
+        None => return Response::with((status::NotImplemented, "Rendering pastes as images is not available.\n"))
+    };
+    if buffer.lines().count() > png::MAX_LINES {
+        return Response::with((status::BadRequest, format!(
+            "Invalid request: only pastes of up to {} lines can be rendered as images.\n", png::MAX_LINES)));
+    }
+    let lines = match highlight_ranges(buffer, lang, theme) {
+        Ok(lines) => lines,
+        Err(s) => return Response::with((status::BadRequest, format!("Invalid request: {}.\n", s)))
+    };
+    let background = theme.settings.background.unwrap_or(Color { r: 0x2d, g: 0x2d, b: 0x2d, a: 0xff });
+    match png::render(&lines, background, font) {
+        Ok(image) => {
+            let mut resp = Response::with((status::Ok, image));
+            resp.set_mut(Header(ContentType(Mime(TopLevel::Image, SubLevel::Png, vec![]))));
+            resp
+        },
+        Err(e) => Response::with((status::InternalServerError, format!("Could not render the paste: {}.\n", e)))
+    }
+}
+
+fn healthz(_: &mut Request) -> IronResult<Response> {
+    if HMAC_KEY.is_empty() {
+        return Ok(Response::with((status::ServiceUnavailable, "No HMAC key loaded.\n")));
+    }
+    if let Err(e) = STORAGE.check() {
+        return Ok(Response::with((status::ServiceUnavailable, format!("Storage unavailable: {}.\n", e))));
+    }
+    Ok(Response::with((status::Ok, "OK\n")))
+}
+
+// The pastes submitted with the API token the request carries.
+fn mine(req: &mut Request) -> IronResult<Response> {
+    let token = match request_token(req) {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            let mut resp = Response::with((status::Unauthorized, "An API token is required.\n"));
+            resp.headers.set_raw("WWW-Authenticate", vec![b"Bearer".to_vec()]);
+            return Ok(resp);
+        },
+        Err(reason) => return Ok(Response::with((status::BadRequest, format!("Invalid API token: {}.\n", reason))))
+    };
+    let mut pastes = Vec::new();
+    for id in itry!(STORAGE.list_owned(&token_hash(&token))) {
+        // expired or deleted in the meantime
+        let meta = match STORAGE.load_meta(&id) {
+            Some(ref meta) if meta.is_expired() => continue,
+            Some(meta) => meta,
+            None => continue
+        };
+        let size = match STORAGE.size(&id) {
+            Ok(size) => size,
+            Err(_) => continue
+        };
+        let mut entry = BTreeMap::new();
+        entry.insert("id".to_string(), id.to_json());
+        entry.insert("title".to_string(), meta.title.to_json());
+        entry.insert("size".to_string(), size.to_json());
+        pastes.push(Json::Object(entry));
+    }
+    let mut resp = Response::with((status::Ok, Json::Array(pastes).to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    Ok(resp)
+}
+
+// The pastes carrying a tag, TAG_PAGE_SIZE at a time, as JSON. The query
+// parameter "page" picks which (from 1), and a Link header gives the next one
+// if there is one.
+fn tagged(req: &mut Request) -> IronResult<Response> {
+    let tag = req.extensions.get::<Router>().unwrap().find("tag").unwrap_or("").to_lowercase();
+    if let Err(reason) = validate_tag(&tag) {
+        return Ok(Response::with((status::BadRequest, format!("Invalid tag: {}.\n", reason))));
+    }
+    let page = match query_param(req, "page").map(|page| page.parse::<usize>()) {
+        Some(Ok(page)) if page > 0 => page,
+        None => 1,
+        _ => return Ok(Response::with((status::BadRequest, "Invalid page: must be a number from 1 up.\n")))
+    };
+    let mut pastes = Vec::new();
+    for id in itry!(STORAGE.list_tagged(&tag)) {
+        // expired or deleted in the meantime
+        match STORAGE.load_meta(&id) {
+            Some(ref meta) if meta.is_expired() => {},
+            Some(meta) => pastes.push((id, meta.title)),
+            None => {}
+        }
+    }
+    let start = (page - 1).saturating_mul(TAG_PAGE_SIZE);
+    let listed: Vec<Json> = pastes.iter().skip(start).take(TAG_PAGE_SIZE).map(|&(ref id, ref title)| {
+        let mut entry = BTreeMap::new();
+        entry.insert("id".to_string(), id.to_json());
+        entry.insert("title".to_string(), title.to_json());
+        Json::Object(entry)
+    }).collect();
+    let mut resp = Response::with((status::Ok, Json::Array(listed).to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    if pastes.len() > start.saturating_add(TAG_PAGE_SIZE) {
+        let next = format!("<{}/tags/{}?page={}>; rel=\"next\"", base_url(req), tag, page + 1);
+        resp.headers.set_raw("Link", vec![next.into_bytes()]);
+    }
+    Ok(resp)
+}
+
+// Every stored paste, as JSON, for the admin to moderate them. The query
+// parameters "page" (from 1) and "per_page" pick which, and a Link header
+// gives the next page if there is one. Only that page's pastes are looked at,
+// so that this stays quick however many there are. Pastes are listed with
+// the hash of the IP address they were submitted from (see submitter_hash()),
+// which is enough to tell which came from the same place.
+fn admin_pastes(req: &mut Request) -> IronResult<Response> {
+    if let Some(resp) = admin_refusal(req) {
+        return Ok(resp);
+    }
+    let page = match query_param(req, "page").map(|page| page.parse::<usize>()) {
+        Some(Ok(page)) if page > 0 => page,
+        None => 1,
+        _ => return Ok(Response::with((status::BadRequest, "Invalid page: must be a number from 1 up.\n")))
+    };
+    let per_page = match query_param(req, "per_page").map(|per_page| per_page.parse::<usize>()) {
+        Some(Ok(per_page)) if per_page > 0 && per_page <= ADMIN_MAX_PER_PAGE => per_page,
+        None => ADMIN_PER_PAGE,
+        _ => return Ok(Response::with((status::BadRequest, format!(
+            "Invalid per_page: must be a number from 1 to {}.\n", ADMIN_MAX_PER_PAGE))))
+    };
+    let offset = (page - 1).saturating_mul(per_page);
+    let now = UTC::now().timestamp();
+    let mut pastes = Vec::new();
+    for id in itry!(STORAGE.list_page(offset, per_page)) {
+        // deleted in the meantime
+        let (size, modified) = match (STORAGE.size(&id), STORAGE.modified(&id)) {
+            (Ok(size), Ok(modified)) => (size, modified),
+            _ => continue
+        };
+        let meta = STORAGE.load_meta(&id);
+        let mut entry = BTreeMap::new();
+        entry.insert("id".to_string(), id.to_json());
+        entry.insert("size".to_string(), size.to_json());
+        entry.insert("modified".to_string(), UTC.timestamp(modified, 0).to_rfc3339().to_json());
+        entry.insert("age_secs".to_string(), (now - modified).to_json());
+        entry.insert("submitter".to_string(), meta.and_then(|meta| meta.submitter).to_json());
+        pastes.push(Json::Object(entry));
+    }
+    let mut resp = Response::with((status::Ok, Json::Array(pastes).to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    if itry!(STORAGE.count()) > offset.saturating_add(per_page) as u64 {
+        let next = format!("<{}/admin/pastes?page={}&per_page={}>; rel=\"next\"", base_url(req), page + 1, per_page);
+        resp.headers.set_raw("Link", vec![next.into_bytes()]);
+    }
+    Ok(resp)
+}
+
+// Deletes any paste without its key, for the admin to take down abusive ones.
+// Such pastes are deleted outright, even if others submitted them too (see
+// deduplicate()), rather than put in the trash.
+fn admin_delete(req: &mut Request) -> IronResult<Response> {
+    if let Some(resp) = admin_refusal(req) {
+        return Ok(resp);
+    }
+    let id = {
+        let params = req.extensions.get::<Router>().unwrap();
+        let id = params.find("paste_id").unwrap_or("");
+        match params.find("namespace") {
+            Some(namespace) => format!("{}/{}", namespace, id),
+            None => id.to_string()
+        }
+    };
+    if let Err(reason) = validate_paste_id(&id) {
+        return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))));
+    }
+    let _writing = WRITES.read().unwrap();
+    let _lock = META_LOCK.lock().unwrap();
+    if !STORAGE.exists(&id) {
+        return Ok(missing_paste(&id));
+    }
+    itry!(remove_paste(&id));
+    itry!(STORAGE.bury(&id, "deleted"));
+    PASTES_DELETED.fetch_add(1, Ordering::Relaxed);
+    println!("[{}] [{}] Admin deleted paste {}", req.remote_addr, UTC::now().format("%Y-%m-%d %H:%M:%S"), id);
+    Ok(Response::with((status::Ok, format!("Paste {} deleted.\n", id))))
+}
+
+// The response to a request for /admin/* from anybody but the admin, or to
+// every such request if there is no admin.
+fn admin_refusal(req: &Request) -> Option<Response> {
+    if CONFIG.admin_password_hash.is_none() {
+        return Some(Response::with((status::NotFound, "Not found.\n")));
+    }
+    if is_admin(req) {
+        return None;
+    }
+    let mut resp = Response::with((status::Unauthorized, "This requires the admin's username and password.\n"));
+    resp.headers.set_raw("WWW-Authenticate", vec![b"Basic realm=\"pastebin admin\"".to_vec()]);
+    Some(resp)
+}
+
+// Redirects to a paste picked at random from those anybody may view, so
+// never to one which would need a password or be deleted by viewing it.
+fn random_paste(req: &mut Request) -> IronResult<Response> {
+    let ids = itry!(STORAGE.list_viewable(UTC::now().timestamp()));
+    if ids.is_empty() {
+        return Ok(Response::with((status::NotFound, "There are no pastes to pick from.\n")));
+    }
+    let mut rng = OsRng::new().expect("opening the OS random number generator");
+    let id = &ids[rng.gen_range(0, ids.len())];
+    let mut resp = Response::with(status::Found);
+    resp.headers.set(Location(format!("{}/{}", base_url(req), id)));
+    Ok(resp)
+}
+
+// Aggregate figures on the stored pastes, as JSON for curl and the like, or
+// as a page for browsers. Working them out means looking at every paste, so
+// they're only worked out again once they're STATS_CACHE_SECS old. The lock is
+// held meanwhile, so that requests arriving then wait rather than each doing it.
+fn stats(req: &mut Request) -> IronResult<Response> {
+    let stats = {
+        let mut cached = STATS.lock().unwrap();
+        let fresh = cached.as_ref()
+            .map_or(false, |&(computed, _)| computed.elapsed() < time::Duration::from_secs(STATS_CACHE_SECS));
+        if !fresh {
+            *cached = Some((time::Instant::now(), itry!(STORAGE.stats())));
+        }
+        cached.as_ref().unwrap().1.clone()
+    };
+    let now = UTC::now().timestamp();
+    let mut data = BTreeMap::new();
+    data.insert("pastes".to_string(), stats.pastes.to_json());
+    data.insert("bytes".to_string(), stats.bytes.to_json());
+    if stats.pastes > 0 {
+        data.insert("average_bytes".to_string(), (stats.bytes / stats.pastes).to_json());
+    }
+    for &(name, time) in &[("oldest", stats.oldest), ("newest", stats.newest)] {
+        if let Some(time) = time {
+            data.insert(name.to_string(), UTC.timestamp(time, 0).to_rfc3339().to_json());
+            data.insert(format!("{}_age_secs", name), (now - time).to_json());
+        }
+    }
+    let mut resp = Response::new();
+    if wants_json(req) || is_curl(req) {
+        resp.set_mut(Json::Object(data).to_string()).set_mut(Header(ContentType::json())).set_mut(status::Ok);
+    } else {
+        resp.set_mut(Template::new("stats_html", data)).set_mut(status::Ok);
+    }
+    Ok(resp)
+}
+
+// In the Prometheus text exposition format.
+fn metrics(_: &mut Request) -> IronResult<Response> {
+    if !CONFIG.metrics {
+        return Ok(Response::with((status::NotFound, "Metrics are disabled.\n")));
+    }
+    let metrics = [
+        ("pastebin_pastes_created_total", "counter", "Pastes submitted.", PASTES_CREATED.load(Ordering::Relaxed) as u64),
+        ("pastebin_pastes_retrieved_total", "counter", "Pastes viewed.", PASTES_RETRIEVED.load(Ordering::Relaxed) as u64),
+        ("pastebin_pastes_deleted_total", "counter", "Pastes deleted by their owners.", PASTES_DELETED.load(Ordering::Relaxed) as u64),
+        ("pastebin_pastes_replaced_total", "counter", "Pastes replaced by their owners.", PASTES_REPLACED.load(Ordering::Relaxed) as u64),
+        ("pastebin_pastes", "gauge", "Pastes currently stored.", itry!(STORAGE.count())),
+        ("pastebin_stored_bytes", "gauge", "Bytes taken up by stored pastes, their previous versions and the trash.", *DISK_USAGE.lock().unwrap() as u64)
+    ];
+    let mut body = String::new();
+    for &(name, kind, help, value) in metrics.iter() {
+        body.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+                               name = name, help = help, kind = kind, value = value));
+    }
+    let mut resp = Response::with((status::Ok, body));
+    resp.headers.set_raw("Content-Type", vec![b"text/plain; version=0.0.4".to_vec()]);
+    Ok(resp)
+}
+
+fn openapi_spec(req: &mut Request) -> IronResult<Response> {
+    let mut resp = Response::with((status::Ok, openapi::spec(&CONFIG, &base_url(req)).to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    Ok(resp)
+}
+
+fn themes(_: &mut Request) -> IronResult<Response> {
+    let names: Vec<Json> = THEME_SET.themes.keys().map(|name| name.to_json()).collect();
+    let mut resp = Response::with((status::Ok, Json::Array(names).to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    Ok(resp)
+}
+
+// "Plain Text" is left out since highlight() refuses it anyway.
+fn languages(_: &mut Request) -> IronResult<Response> {
+    let syntaxes: Vec<Json> = with_syntax_set(|ss| {
+        ss.syntaxes().iter()
+            .filter(|syntax| !syntax.hidden && syntax.name != "Plain Text")
+            .map(|syntax| {
+                let mut entry = BTreeMap::new();
+                entry.insert("name".to_string(), syntax.name.to_json());
+                entry.insert("extensions".to_string(), syntax.file_extensions.to_json());
+                Json::Object(entry)
+            })
+            .collect()
+    });
+    let mut resp = Response::with((status::Ok, Json::Array(syntaxes).to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    Ok(resp)
+}
+
+// retrieve_paste() already serves the paste unmodified, so all that is left is
+// to stop clients from guessing at the content type.
+fn retrieve_raw(req: &mut Request) -> IronResult<Response> {
+    let id = paste_id(req);
+    let mut resp = try!(retrieve_paste(req, true));
+    let served = resp.status == Some(status::Ok) || resp.status == Some(status::PartialContent);
+    if served {
+        // binary pastes already say so
+        let text = resp.headers.get::<ContentType>().is_none();
+        let (mime, attachment) = itry!(raw_content_type(&id, text));
+        resp.set_mut(Header(ContentType(mime)));
+        if attachment {
+            resp.headers.set_raw("Content-Disposition", vec![b"attachment".to_vec()]);
+        }
+        resp.headers.set_raw("X-Content-Type-Options", vec![b"nosniff".to_vec()]);
+    }
+    Ok(resp)
+}
+
+// What to serve a paste as raw: the type its filename's extension suggests, or
+// failing that, plain text, or for binary pastes the type their first few
+// bytes suggest. Browsers run the scripts in some types, which would then be
+// running on our origin, so those are defused: HTML is shown as its source,
+// and SVG and XML are downloaded (the second value) rather than shown.
+fn raw_content_type(id: &str, text: bool) -> io::Result<(Mime, bool)> {
+    let guessed = STORAGE.load_meta(id)
+        .and_then(|meta| meta.filename)
+        .and_then(|filename| Path::new(&filename).extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()))
+        .and_then(|ext| mime_guess::get_mime_type_opt(&ext));
+    let mime = match guessed {
+        Some(mime) => mime,
+        None if text => return Ok((ContentType::plaintext().0, false)),
+        None => try!(sniff_content_type(id))
+    };
+    Ok(match format!("{}/{}", mime.0, mime.1).as_str() {
+        "text/html" | "application/xhtml+xml" => (ContentType::plaintext().0, false),
+        "image/svg+xml" | "text/xml" | "application/xml" => (mime, true),
+        _ => (mime, false)
+    })
+}
+
+fn sniff_content_type(id: &str) -> io::Result<Mime> {
+    let mut start = Vec::new();
+    if let Some(paste) = try!(STORAGE.open(id)) {
+        try!(paste.take(8).read_to_end(&mut start));
+    }
+    let signatures: [(&[u8], Mime); 6] = [
+        (&b"\x89PNG"[..], Mime(TopLevel::Image, SubLevel::Png, vec![])),
+        (&b"\xff\xd8\xff"[..], Mime(TopLevel::Image, SubLevel::Jpeg, vec![])),
+        (&b"GIF8"[..], Mime(TopLevel::Image, SubLevel::Gif, vec![])),
+        (&b"%PDF-"[..], Mime(TopLevel::Application, SubLevel::Ext("pdf".to_string()), vec![])),
+        (&b"PK\x03\x04"[..], Mime(TopLevel::Application, SubLevel::Ext("zip".to_string()), vec![])),
+        (&b"\x1f\x8b"[..], Mime(TopLevel::Application, SubLevel::Ext("gzip".to_string()), vec![]))
+    ];
+    for &(ref signature, ref mime) in signatures.iter() {
+        if start.starts_with(signature) {
+            return Ok(mime.clone());
+        }
+    }
+    Ok(Mime(TopLevel::Application, SubLevel::OctetStream, vec![]))
+}
+
+fn download(req: &mut Request) -> IronResult<Response> {
+    let id = paste_id(req);
+    if let Err(reason) = validate_paste_id(&id) {
+        return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))));
+    }
+    // read before retrieving, since burning the paste also removes its metadata
+    let ext = STORAGE.load_meta(&id)
+        .and_then(|meta| meta.filename)
+        .and_then(|filename| Path::new(&filename).extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_string()))
+        .and_then(|ext| if ext.chars().all(|c| c.is_alphanumeric()) { Some(ext) } else { None });
+
+    let mut resp = try!(retrieve_paste(req, true));
+    if resp.status == Some(status::Ok) || resp.status == Some(status::PartialContent) {
+        let (filename, mime) = match ext {
+            Some(ext) => {
+                let mime = mime_guess::get_mime_type_opt(&ext)
+                    .unwrap_or(Mime(TopLevel::Application, SubLevel::OctetStream, vec![]));
+                (format!("{}.{}", id, ext), mime)
+            },
+            None => (id, Mime(TopLevel::Application, SubLevel::OctetStream, vec![]))
+        };
+        // namespaced IDs aren't valid filenames as they are
+        let disposition = format!("attachment; filename=\"{}\"", filename.replace('/', "-"));
+        resp.headers.set_raw("Content-Disposition", vec![disposition.into_bytes()]);
+        resp.set_mut(Header(ContentType(mime)));
+    }
+    Ok(resp)
+}
+
+// A PNG QR code for the paste's view URL. The optional "size" query parameter
+// gives the width in pixels, which is rounded down to fit the code exactly.
+fn qr_code(req: &mut Request) -> IronResult<Response> {
+    let id = paste_id(req);
+    if let Err(reason) = validate_paste_id(&id) {
+        return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))));
+    }
+    if STORAGE.load_meta(&id).map_or(false, |meta| meta.is_expired()) {
+        return Ok(gone_paste(&id, "expired"));
+    }
+    if !STORAGE.exists(&id) {
+        return Ok(missing_paste(&id));
+    }
+    let size = match query_param(req, "size") {
+        Some(size) => match size.parse::<u32>() {
+            Ok(size) if QR_MIN_SIZE <= size && size <= QR_MAX_SIZE => size,
+            _ => return Ok(Response::with((status::BadRequest, format!(
+                "Invalid request: size must be between {} and {}.\n", QR_MIN_SIZE, QR_MAX_SIZE))))
+        },
+        None => QR_DEFAULT_SIZE
+    };
+
+    let url = format!("{base_url}/{id}", base_url = base_url(req), id = id);
+    let code = itry!(QrCode::new(url.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e))));
+    let modules = code.width() as u32 + 8; // including the quiet zone around the code
+    let scale = std::cmp::max(1, size / modules);
+    let width = modules * scale;
+    let colors = code.to_colors();
+    let mut pixels = Vec::with_capacity((width * width) as usize);
+    for y in 0..width {
+        for x in 0..width {
+            let (mx, my) = ((x / scale) as i64 - 4, (y / scale) as i64 - 4);
+            let inside = 0 <= mx && mx < code.width() as i64 && 0 <= my && my < code.width() as i64;
+            let dark = inside && colors[(my * code.width() as i64 + mx) as usize] == QrColor::Dark;
+            pixels.push(if dark { 0 } else { 255 });
+        }
+    }
+    let mut image = Vec::new();
+    itry!(PNGEncoder::new(&mut image).encode(&pixels, width, width, ColorType::Gray(8)));
+    let mut resp = Response::with((status::Ok, image));
+    resp.set_mut(Header(ContentType(Mime(TopLevel::Image, SubLevel::Png, vec![]))));
+    Ok(resp)
+}
+
+// What GET /<id> describes as JSON, as a page for browsers, so that a paste can
+// be looked into before (say) opening something huge. Like the JSON, it
+// doesn't require the paste's password.
+fn info(req: &mut Request) -> IronResult<Response> {
+    let id = paste_id(req);
+    if let Err(reason) = validate_paste_id(&id) {
+        return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))));
+    }
+    let meta = STORAGE.load_meta(&id);
+    if meta.as_ref().map_or(false, |meta| meta.is_expired()) {
+        return Ok(gone_paste(&id, "expired"));
+    }
+    let data = match paste_info(&id, meta.as_ref()) {
+        Ok((Json::Object(data), _)) => data,
+        _ => return Ok(missing_paste(&id))
+    };
+    let mut resp = Response::new();
+    resp.set_mut(Template::new("info_html", data)).set_mut(status::Ok);
+    Ok(resp)
+}
+
+// The paste as a single-file gist, shaped like those of GitHub's gist API, so
+// that tools which read gists can read pastes too.
+fn gist(req: &mut Request) -> IronResult<Response> {
+    let id = paste_id(req);
+    if let Some(refusal) = refuse_access(req, &id) {
+        return Ok(refusal);
+    }
+    let meta = STORAGE.load_meta(&id);
+    // burning them is left to retrieve(), which they can only be viewed once with anyway
+    if meta.as_ref().map_or(false, |meta| meta.burn) {
+        return Ok(Response::with((status::Forbidden, format!(
+            "Paste {} can only be viewed once, with GET /{}.\n", id, id))));
+    }
+    let times = itry!(paste_times(&id, meta.as_ref()));
+    let paste = match itry!(STORAGE.load(&id)) {
+        Some(paste) => paste,
+        None => return Ok(missing_paste(&id))
+    };
+    let language = meta.as_ref().and_then(default_language);
+    let filename = match (meta.as_ref().and_then(|meta| meta.filename.clone()), language.as_ref()) {
+        (Some(filename), _) => filename,
+        (None, Some(language)) => format!("{}.{}", id.replace('/', "-"), language),
+        (None, None) => format!("{}.txt", id.replace('/', "-"))
+    };
+    let url = format!("{}/{}", base_url(req), id);
+
+    let mut file = BTreeMap::new();
+    file.insert("filename".to_string(), filename.to_json());
+    file.insert("type".to_string(), if str::from_utf8(&paste).is_ok() { "text/plain" } else { "application/octet-stream" }.to_json());
+    file.insert("language".to_string(), language.map(|language| language_name(&language)).to_json());
+    file.insert("raw_url".to_string(), format!("{}/raw", url).to_json());
+    file.insert("size".to_string(), paste.len().to_json());
+    file.insert("truncated".to_string(), false.to_json());
+    file.insert("content".to_string(), String::from_utf8_lossy(&paste).to_json());
+    let mut files = BTreeMap::new();
+    files.insert(filename, Json::Object(file));
+
+    let mut gist = BTreeMap::new();
+    gist.insert("id".to_string(), id.to_json());
+    gist.insert("html_url".to_string(), url.to_json());
+    gist.insert("description".to_string(), meta.as_ref().and_then(|meta| meta.title.clone()).to_json());
+    gist.insert("public".to_string(), meta.as_ref().map_or(true, |meta| meta.password.is_none()).to_json());
+    gist.insert("created_at".to_string(), UTC.timestamp(times.0, 0).to_rfc3339().to_json());
+    gist.insert("updated_at".to_string(), UTC.timestamp(times.0, 0).to_rfc3339().to_json());
+    gist.insert("files".to_string(), Json::Object(files));
+    let mut resp = Response::with((status::Ok, Json::Object(gist).to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    Ok(resp)
+}
+
+// The name of the language with the given extension, e.g. "Rust" for "rs".
+fn language_name(ext: &str) -> String {
+    let ext = ext.to_string();
+    with_syntax_set(move |ss| ss.find_syntax_by_extension(&ext).map_or(ext.clone(), |syntax| syntax.name.clone()))
+}
+
+// Lists the previous versions of a paste kept by replace(), oldest first, as JSON.
+fn versions(req: &mut Request) -> IronResult<Response> {
+    let id = paste_id(req);
+    if let Some(refusal) = refuse_access(req, &id) {
+        return Ok(refusal);
+    }
+    let mut versions = Vec::new();
+    for (version, replaced) in itry!(STORAGE.list_versions(&id)) {
+        // pruned in the meantime
+        let size = match itry!(STORAGE.load_version(&id, version)) {
+            Some(paste) => paste.len(),
+            None => continue
+        };
+        let mut entry = BTreeMap::new();
+        entry.insert("version".to_string(), version.to_json());
+        entry.insert("replaced".to_string(), UTC.timestamp(replaced, 0).to_rfc3339().to_json());
+        entry.insert("size".to_string(), size.to_json());
+        versions.push(Json::Object(entry));
+    }
+    let mut resp = Response::with((status::Ok, Json::Array(versions).to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    Ok(resp)
+}
+
+// A previous version of a paste, served as GET /<id>/raw would serve the paste.
+fn retrieve_version(req: &mut Request) -> IronResult<Response> {
+    let (id, version) = {
+        let params = req.extensions.get::<Router>().unwrap();
+        (paste_id(req), params.find("version").unwrap_or("").to_string())
+    };
+    if let Some(refusal) = refuse_access(req, &id) {
+        return Ok(refusal);
+    }
+    let version = match version.parse::<u64>() {
+        Ok(version) => version,
+        Err(_) => return Ok(Response::with((status::BadRequest, "Invalid request: versions are numbered.\n")))
+    };
+    let paste = match itry!(STORAGE.load_version(&id, version)) {
+        Some(paste) => paste,
+        None => return Ok(Response::with((status::NotFound, format!("Paste {} has no version {}\n", id, version))))
+    };
+    let content_type = if str::from_utf8(&paste).is_ok() {
+        ContentType::plaintext()
+    } else {
+        ContentType(Mime(TopLevel::Application, SubLevel::OctetStream, vec![]))
+    };
+    let mut resp = Response::with((status::Ok, paste));
+    resp.set_mut(Header(content_type));
+    resp.headers.set_raw("X-Content-Type-Options", vec![b"nosniff".to_vec()]);
+    Ok(resp)
+}
+
+// A unified diff between two versions of a paste, either of which may be
+// "current". Browsers get it as a page with added and removed lines coloured.
+fn diff_versions(req: &mut Request) -> IronResult<Response> {
+    let (id, from, to) = {
+        let params = req.extensions.get::<Router>().unwrap();
+        (paste_id(req),
+         params.find("from").unwrap_or("").to_string(),
+         params.find("to").unwrap_or("").to_string())
+    };
+    if let Some(refusal) = refuse_access(req, &id) {
+        return Ok(refusal);
+    }
+    let mut texts = Vec::new();
+    for version in &[&from, &to] {
+        let paste = if *version == "current" {
+            itry!(STORAGE.load(&id))
+        } else {
+            match version.parse::<u64>() {
+                Ok(n) => itry!(STORAGE.load_version(&id, n)),
+                Err(_) => return Ok(Response::with((status::BadRequest,
+                                                    "Invalid request: versions are numbered, or \"current\".\n")))
+            }
+        };
+        let paste = match paste {
+            Some(paste) => paste,
+            None => return Ok(Response::with((status::NotFound, format!("Paste {} has no version {}\n", id, version))))
+        };
+        match String::from_utf8(paste) {
+            Ok(text) => texts.push(text),
+            Err(_) => return Ok(Response::with((status::BadRequest, format!(
+                "Invalid request: version {} of paste {} isn't text.\n", version, id))))
+        }
+    }
+    let lines = unified_diff(&texts[0], &texts[1], &format!("{}/{}", id, from), &format!("{}/{}", id, to));
+
+    if is_curl(req) {
+        let mut body = String::new();
+        for &(_, ref line) in &lines {
+            body.push_str(line);
+            body.push('\n');
+        }
+        let mut resp = Response::with((status::Ok, body));
+        resp.set_mut(Header(ContentType::plaintext()));
+        return Ok(resp);
+    }
+    let lines: Vec<Json> = lines.into_iter()
+        .map(|(kind, text)| {
+            let mut line = BTreeMap::new();
+            line.insert("kind".to_string(), kind.to_json());
+            line.insert("text".to_string(), text.to_json());
+            Json::Object(line)
+        })
+        .collect();
+    let mut data = BTreeMap::new();
+    data.insert("id".to_string(), id.to_json());
+    data.insert("from".to_string(), from.to_json());
+    data.insert("to".to_string(), to.to_json());
+    data.insert("lines".to_string(), lines.to_json());
+    let mut resp = Response::new();
+    resp.set_mut(Template::new("diff_html", data)).set_mut(status::Ok);
+    Ok(resp)
+}
+
+// The lines of a unified diff from `old` to `new` with three lines of context,
+// each along with what kind of line it is (for colouring them in browsers).
+fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> Vec<(&'static str, String)> {
+    const CONTEXT: usize = 3;
+    let changes: Vec<(char, &str)> = diff::lines(old, new).into_iter()
+        .map(|change| match change {
+            diff::Result::Left(line) => ('-', line),
+            diff::Result::Both(line, _) => (' ', line),
+            diff::Result::Right(line) => ('+', line)
+        })
+        .collect();
+
+    // changes close enough together to share their context make up one hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, &(kind, _)) in changes.iter().enumerate() {
+        if kind == ' ' {
+            continue;
+        }
+        let start = i.saturating_sub(CONTEXT);
+        let end = std::cmp::min(i + CONTEXT + 1, changes.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end))
+        }
+    }
+
+    let mut lines = vec![("header", format!("--- {}", old_name)), ("header", format!("+++ {}", new_name))];
+    // line numbers in the old and new texts, as of the start of the hunk
+    let (mut old_line, mut new_line, mut done) = (1, 1, 0);
+    for (start, end) in hunks {
+        for &(kind, _) in &changes[done..start] {
+            if kind != '+' {
+                old_line += 1;
+            }
+            if kind != '-' {
+                new_line += 1;
+            }
+        }
+        let old_len = changes[start..end].iter().filter(|&&(kind, _)| kind != '+').count();
+        let new_len = changes[start..end].iter().filter(|&&(kind, _)| kind != '-').count();
+        // an empty range is numbered after the line it follows
+        lines.push(("hunk", format!("@@ -{},{} +{},{} @@",
+                                    if old_len == 0 { old_line - 1 } else { old_line }, old_len,
+                                    if new_len == 0 { new_line - 1 } else { new_line }, new_len)));
+        for &(kind, line) in &changes[start..end] {
+            let class = match kind {
+                '-' => "removed",
+                '+' => "added",
+                _ => "context"
+            };
+            lines.push((class, format!("{}{}", kind, line)));
+        }
+        old_line += old_len;
+        new_line += new_len;
+        done = end;
+    }
+    lines
+}
+
+// Anything derived from a paste (such as its previous versions) is only for
+// those who could view the paste itself. Yields the response refusing them.
+fn refuse_access(req: &mut Request, id: &str) -> Option<Response> {
+    if let Err(reason) = validate_paste_id(id) {
+        return Some(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))));
+    }
+    let meta = STORAGE.load_meta(id);
+    if meta.as_ref().map_or(false, |meta| meta.is_expired()) {
+        return Some(gone_paste(id, "expired"));
+    }
+    if !STORAGE.exists(id) {
+        return Some(missing_paste(id));
+    }
+    if !password_valid(req, id, meta.as_ref()) {
+        return Some(Response::with((status::Unauthorized, format!("Paste {} requires a valid password\n", id))));
+    }
+    None
+}
+
+// Whether the request carries the paste's password, if it has one.
+fn password_valid(req: &mut Request, id: &str, meta: Option<&PasteMeta>) -> bool {
+    match meta.and_then(|meta| meta.password.as_ref()) {
+        Some(expected) => match request_password(req) {
+            Some(password) => fixed_time_eq(password_hash(id, &password).as_bytes(), expected.as_bytes()),
+            None => false
+        },
+        None => true
+    }
+}
+
+// Asks browser users whether they really want to delete the paste, which is then
+// deleted by the form POSTing back to the same URL.
+fn confirm_delete(req: &mut Request) -> IronResult<Response> {
+    let id = match validate_key_id(req) {
+        Ok(id) => id,
+        Err(reason) => return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))))
+    };
+    let key = req.extensions.get::<Router>().unwrap().find("key").unwrap_or("").to_string();
+    let title = STORAGE.load_meta(&id).and_then(|meta| meta.title);
+    let mut data = page_data(&id, title.as_ref());
+    data.insert("key".to_string(), key.to_json());
+    let mut resp = Response::new();
+    resp.set_mut(Template::new("delete_html", data)).set_mut(status::Ok);
+    Ok(resp)
+}
+
+fn delete(req: &mut Request) -> IronResult<Response> {
+    let id = match validate_key_id(req) {
+        Ok(id) => id,
+        Err(reason) => return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))))
+    };
+    // delete file
+    let _writing = WRITES.read().unwrap();
+    // held throughout, so that nobody can be handed the paste as it goes
+    let _lock = META_LOCK.lock().unwrap();
+    // a paste shared by several submissions (see deduplicate()) stays until
+    // each of them has been deleted
+    if let Some(mut meta) = STORAGE.load_meta(&id) {
+        if meta.refs > 1 {
+            let key = req.extensions.get::<Router>().unwrap().find("key").unwrap_or("").to_string();
+            // checked again now that nobody else can be letting go of it too
+            if !release_reference(&id, &key, &mut meta) {
+                return Ok(Response::with((status::BadRequest, "Invalid request: Key is not valid.\n")));
+            }
+            itry!(STORAGE.save_meta(&id, &meta));
+            return Ok(Response::with((status::Ok, format!(
+                "Paste {} deleted, but it was also submitted by others, so remains until they delete it too.\n", id))));
+        }
+    }
+    if CONFIG.trash_days == 0 {
+        itry!(remove_paste(&id));
+    } else {
+        // which still takes up the same space
+        itry!(STORAGE.trash(&id));
+    }
+    itry!(STORAGE.bury(&id, "deleted"));
+    PASTES_DELETED.fetch_add(1, Ordering::Relaxed);
+    if CONFIG.trash_days == 0 {
+        return Ok(Response::with((status::Ok, format!("Paste {} deleted.\n", id))));
+    }
+    let key = req.extensions.get::<Router>().unwrap().find("key").unwrap_or("").to_string();
+    Ok(Response::with((status::Ok, format!(
+        "Paste {id} deleted. It can be restored within {days} days with\n\n    curl -X POST {base_url}/{id}/{key}/restore\n",
+        id = id, days = CONFIG.trash_days, base_url = base_url(req), key = key))))
+}
+
+// Takes a deleted paste back out of the trash, given the key it was deleted with.
+fn restore_paste(req: &mut Request) -> IronResult<Response> {
+    let id = paste_id(req);
+    if let Err(reason) = validate_paste_id(&id) {
+        return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))));
+    }
+    let key = req.extensions.get::<Router>().unwrap().find("key").unwrap_or("").to_string();
+    let _writing = WRITES.read().unwrap();
+    let _lock = META_LOCK.lock().unwrap();
+    let meta = match STORAGE.trashed(&id) {
+        Some((_, meta)) => meta,
+        None => return Ok(Response::with((status::NotFound, format!("Paste {} is not in the trash\n", id))))
+    };
+    if !key_valid(&id, &key, meta.as_ref()) {
+        return Ok(Response::with((status::BadRequest, "Invalid request: Key is not valid.\n")));
+    }
+    match STORAGE.restore(&id) {
+        Ok(true) => {},
+        // purged in the meantime
+        Ok(false) => return Ok(Response::with((status::NotFound, format!("Paste {} is not in the trash\n", id)))),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            return Ok(Response::with((status::Conflict, format!("Paste {} can't be restored: its ID has been taken since.\n", id))));
+        },
+        Err(e) => return Err(IronError::new(e, status::InternalServerError))
+    }
+    Ok(Response::with((status::Ok, format!("{base_url}/{id} restored.\n", base_url = base_url(req), id = id))))
+}
+
+fn replace(req: &mut Request) -> IronResult<Response> {
+    let id = paste_id(req);
+    if validate_paste_id(&id).is_ok() && !STORAGE.exists(&id) {
+        if !if_match(req, None) {
+            return Ok(precondition_failed(&id));
+        }
+        return upsert(req, &id);
+    }
+    let id = match validate_key_id(req) {
+        Ok(id) => id,
+        Err(reason) => return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))))
+    };
+    if declared_over(req, CONFIG.max_paste_bytes as u64) {
+        return Ok(Response::with(too_large()));
+    }
+    // write body
+    let paste = try!(read_body(req).map_err(body_error));
+    // verify max size before saving it
+    if let Err(reason) = check_paste_size(&paste) {
+        return Ok(Response::with(reason));
+    }
+    let _writing = WRITES.read().unwrap();
+    // held throughout, so that a concurrent append() can't end up in between
+    let _lock = META_LOCK.lock().unwrap();
+    if is_shared(&id) {
+        return Ok(shared_paste(&id));
+    }
+    // kept in case replacing the paste was a mistake, apart from those of
+    // burn-after-reading pastes, whose versions could be read over and over
+    let burn = STORAGE.load_meta(&id).map_or(false, |meta| meta.burn);
+    let keep_version = CONFIG.versions > 0 && !burn;
+    // also kept to put back if the new contents turn out not to fit
+    let previous = if keep_version || req.headers.has::<IfMatch>() || CONFIG.disk_quota_bytes > 0 {
+        itry!(STORAGE.load(&id))
+    } else {
+        None
+    };
+    // so that a client editing what it read earlier doesn't silently undo
+    // whatever somebody else has replaced it with since
+    if !if_match(req, previous.as_ref().map(|previous| content_etag(previous)).as_ref()) {
+        return Ok(precondition_failed(&id));
+    }
+    // the old contents still take up space as a version, until the oldest is dropped
+    let old_len = if keep_version { 0 } else { itry!(STORAGE.size(&id)) as usize };
+    itry!(STORAGE.save(&id, &paste));
+    if !charge_disk_usage(itry!(STORAGE.size(&id)) as usize, old_len) {
+        if let Some(previous) = previous {
+            itry!(STORAGE.save(&id, &previous));
+        }
+        return Ok(Response::with(server_full()));
+    }
+    if keep_version {
+        if let Some(previous) = previous {
+            itry!(STORAGE.save_version(&id, &previous, CONFIG.versions));
+        }
+    }
+    // replacing a paste restarts its lifetime
+    let mut meta = STORAGE.load_meta(&id).unwrap_or_else(|| PasteMeta::new(CONFIG.max_ttl()));
+    meta.renew();
+    meta.hash = None; // the new contents are nobody else's to share
+    itry!(STORAGE.save_meta(&id, &meta));
+    PASTES_REPLACED.fetch_add(1, Ordering::Relaxed);
+    Ok(Response::with((status::Ok, format!(
+        "{base_url}/{id} overwritten.\n\nThis paste will be deleted in {ttl}.\n",
+        base_url = base_url(req), id = id, ttl = describe_ttl(meta.ttl)))))
+}
+
+
+
+
+
+// Everybody who submitted a deduplicated paste (see deduplicate()) holds an
+// edit key to it, so none of them may change it for the others; deleting it
+// only lets go of their own share of it.
+fn is_shared(id: &str) -> bool {
+    STORAGE.load_meta(id).map_or(false, |meta| meta.refs > 1)
+}
+
+fn shared_paste(id: &str) -> Response {
+    Response::with((status::Conflict, format!(
+        "Paste {} was also submitted by others, so can't be changed; submit your version as a new paste.\n", id)))
+}
+
+// PUT to a paste which doesn't exist (yet, or any more) creates it, provided
+// that the key is the one gen_key() would give it, so that an Edit URL stays
+// good for recreating its paste after it has expired or been deleted. Random
+// keys aren't kept once their pastes are gone, so there's nothing to check
+// them against.
+fn upsert(req: &mut Request, id: &str) -> IronResult<Response> {
+    let key = req.extensions.get::<Router>().unwrap().find("key").unwrap_or("").to_string();
+    if CONFIG.random_keys {
+        return Ok(Response::with((status::BadRequest, format!("Invalid request: Paste {} does not exist.\n", id))));
+    }
+    if !fixed_time_eq(key.as_bytes(), gen_key(id).as_bytes()) {
+        return Ok(Response::with((status::BadRequest, "Invalid request: Key is not valid.\n")));
+    }
+    if declared_over(req, CONFIG.max_paste_bytes as u64) {
+        return Ok(Response::with(too_large()));
+    }
+    let paste = try!(read_body(req).map_err(body_error));
+    match store_paste(req, paste, &Source::Query, None, Some(id)) {
+        Ok(submission) => Ok(submitted(req, &submission)),
+        Err((status, message)) => Ok(Response::with((status, message)))
+    }
+}
+
+// Pushes back when a paste expires, to the lifetime given as "expires" (or else
+// the one it has had so far) from now. Each paste may only be renewed
+// CONFIG.max_renewals times, so that none can be kept around forever.
+fn renew_paste(req: &mut Request) -> IronResult<Response> {
+    let id = match validate_key_id(req) {
+        Ok(id) => id,
+        Err(reason) => return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))))
+    };
+    let ttl = match query_param(req, "expires").map(|expires| parse_ttl(&expires)) {
+        Some(Ok(ttl)) => Some(ttl),
+        Some(Err(reason)) => return Ok(Response::with((status::BadRequest, format!("Invalid expiry: {}.\n", reason)))),
+        None => None
+    };
+    let _writing = WRITES.read().unwrap();
+    let _lock = META_LOCK.lock().unwrap();
+    let mut meta = STORAGE.load_meta(&id).unwrap_or_else(|| PasteMeta::new(CONFIG.max_ttl()));
+    if meta.is_expired() {
+        return Ok(gone_paste(&id, "expired"));
+    }
+    if meta.renewals >= CONFIG.max_renewals {
+        return Ok(Response::with((status::Forbidden, format!("Paste {} may not be renewed again.\n", id))));
+    }
+    if let Some(ttl) = ttl {
+        meta.ttl = ttl;
+    }
+    meta.renew();
+    meta.renewals += 1;
+    itry!(STORAGE.save_meta(&id, &meta));
+    let expires = UTC.timestamp(meta.expires, 0).to_rfc3339();
+    if wants_json(req) {
+        let mut info = BTreeMap::new();
+        info.insert("id".to_string(), id.to_json());
+        info.insert("expires".to_string(), expires.to_json());
+        info.insert("renewals_left".to_string(), (CONFIG.max_renewals - meta.renewals).to_json());
+        let mut resp = Response::with((status::Ok, Json::Object(info).to_string()));
+        resp.set_mut(Header(ContentType::json()));
+        return Ok(resp);
+    }
+    Ok(Response::with((status::Ok, format!(
+        "{base_url}/{id} will now be deleted at {expires}, in {ttl}.\n",
+        base_url = base_url(req), id = id, expires = expires, ttl = describe_ttl(meta.ttl)))))
+}
+
+fn append(req: &mut Request) -> IronResult<Response> {
+    let id = match validate_key_id(req) {
+        Ok(id) => id,
+        Err(reason) => return Ok(Response::with((status::BadRequest, format!("Invalid request: {}.\n", reason))))
+    };
+    if declared_over(req, CONFIG.max_paste_bytes as u64) {
+        return Ok(Response::with(too_large()));
+    }
+    let addition = try!(read_body(req).map_err(body_error));
+    if addition.is_empty() {
+        return Ok(Response::with((status::BadRequest, "No data to append.\n")))
+    }
+    let _writing = WRITES.read().unwrap();
+    // held throughout, so that concurrent appends can't lose each other's data
+    let _lock = META_LOCK.lock().unwrap();
+    if is_shared(&id) {
+        return Ok(shared_paste(&id));
+    }
+    let mut paste = match itry!(STORAGE.load(&id)) {
+        Some(paste) => paste,
+        None => return Ok(Response::with((status::NotFound, format!("Paste {} does not exist\n", id))))
+    };
+    // verify max size before saving it
+    if paste.len() + addition.len() > CONFIG.max_paste_bytes {
+        return Ok(Response::with(too_large()))
+    }
+    if disk_full() {
+        return Ok(Response::with(server_full()));
+    }
+    let original_len = paste.len();
+    paste.extend_from_slice(&addition);
+    // the whole paste is saved in one go, so readers see it either before or after
+    let old_len = itry!(STORAGE.size(&id)) as usize;
+    itry!(STORAGE.save(&id, &paste));
+    if !charge_disk_usage(itry!(STORAGE.size(&id)) as usize, old_len) {
+        paste.truncate(original_len);
+        itry!(STORAGE.save(&id, &paste));
+        return Ok(Response::with(server_full()));
+    }
+    // like replacing a paste, appending to it restarts its lifetime
+    let mut meta = STORAGE.load_meta(&id).unwrap_or_else(|| PasteMeta::new(CONFIG.max_ttl()));
+    meta.renew();
+    meta.hash = None;
+    itry!(STORAGE.save_meta(&id, &meta));
+    Ok(Response::with((status::Ok, format!(
+        "{base_url}/{id} is now {size} bytes long.\n\nThis paste will be deleted in {ttl}.\n",
+        base_url = base_url(req), id = id, size = paste.len(), ttl = describe_ttl(meta.ttl)))))
+}
+
+// The ID of the paste the request is for, including its namespace if it has
+// one (see NamespaceMiddleware).
+fn paste_id(req: &Request) -> String {
+    let id = req.extensions.get::<Router>().unwrap().find("paste_id").unwrap_or("");
+    match req.extensions.get::<Namespace>() {
+        Some(namespace) => format!("{}/{}", namespace, id),
+        None => id.to_string()
+    }
+}
+
+fn validate_key_id(req: &Request) -> Result<String, String> {
+    let params = req.extensions.get::<Router>().unwrap();
+    let id = paste_id(req);
+    try!(validate_paste_id(&id));
+    if !STORAGE.exists(&id) {
+        return Err(format!("Paste {} does not exist", id));
+    }
+    let key = params.find("key").unwrap_or("");
+    if !key_valid(&id, key, STORAGE.load_meta(&id).as_ref()) {
+        return Err("Key is not valid".to_string());
+    }
+    Ok(id)
+}
+
+fn key_valid(id: &str, key: &str, meta: Option<&PasteMeta>) -> bool {
+    key_holder(id, key, meta).is_some()
+}
+
+// Whose key a key is: that of the submission which stored the paste, or of
+// one of those which were handed it since (see deduplicate()).
+#[derive(Debug, PartialEq)]
+enum KeyHolder {
+    Original,
+    Reference(usize) // into meta.ref_keys
+}
+
+fn key_holder(id: &str, key: &str, meta: Option<&PasteMeta>) -> Option<KeyHolder> {
+    if let Some(meta) = meta {
+        if let Some(i) = meta.ref_keys.iter().position(|stored| hash_matches(key, stored)) {
+            return Some(KeyHolder::Reference(i));
+        }
+        // the original submission may have let go of its share already
+        if meta.refs as usize <= meta.ref_keys.len() {
+            return None;
+        }
+    }
+    let original = match meta.and_then(|meta| meta.key_hash.as_ref()) {
+        Some(stored) => hash_matches(key, stored),
+        None => fixed_time_eq(key.as_bytes(), gen_key(id).as_bytes())
+    };
+    if original { Some(KeyHolder::Original) } else { None }
+}
+
+// Lets go of the share of a paste which the key holds (see deduplicate()), so
+// that the key can't delete it again. False if the key holds no share (any more).
+fn release_reference(id: &str, key: &str, meta: &mut PasteMeta) -> bool {
+    match key_holder(id, key, Some(meta)) {
+        Some(KeyHolder::Reference(i)) => { meta.ref_keys.remove(i); },
+        Some(KeyHolder::Original) => {},
+        None => return false
+    }
+    meta.refs -= 1;
+    true
+}
+
+// Pastes which did exist until recently are Gone rather than Not Found, so that
+// whoever followed a link to one can tell what became of it.
+fn missing_paste(id: &str) -> Response {
+    match STORAGE.tombstone(id) {
+        Some(reason) => gone_paste(id, &reason),
+        None => Response::with((status::NotFound, format!("Paste {} does not exist\n", id)))
+    }
+}
+
+fn gone_paste(id: &str, reason: &str) -> Response {
+    let what_happened = match reason {
+        "expired" => "has expired",
+        "burnt" => "was deleted after being viewed once",
+        _ => "has been deleted"
+    };
+    Response::with((status::Gone, format!("Paste {} {}\n", id, what_happened)))
+}
+
+fn remove_paste(id: &str) -> io::Result<()> {
+    let len = try!(STORAGE.size(id)) as usize;
+    try!(STORAGE.delete(id));
+    adjust_disk_usage(0, len);
+    Ok(())
+}
+
+// Returns the updated view count, or None for pastes from before metadata existed.
+fn record_view(id: &str) -> io::Result<Option<u64>> {
+    let _lock = META_LOCK.lock().unwrap();
+    let mut meta = match STORAGE.load_meta(id) {
+        Some(meta) => meta,
+        None => return Ok(None)
+    };
+    meta.views += 1;
+    try!(STORAGE.save_meta(id, &meta));
+    Ok(Some(meta.views))
+}
+
+fn adjust_disk_usage(added: usize, removed: usize) {
+    let mut usage = DISK_USAGE.lock().unwrap();
+    *usage = (*usage + added).saturating_sub(removed);
+}
+
+// Like adjust_disk_usage(), but for storing something which has just been
+// written, which is refused (leaving the usage as it was) if it would take the
+// usage over the quota. Checking and charging under the one lock means that
+// concurrent writes can't all fit in the same space.
+fn charge_disk_usage(added: usize, removed: usize) -> bool {
+    charge_usage(&DISK_USAGE, CONFIG.disk_quota_bytes, added, removed)
+}
+
+fn charge_usage(usage: &Mutex<usize>, quota: usize, added: usize, removed: usize) -> bool {
+    let mut usage = usage.lock().unwrap();
+    let charged = (*usage + added).saturating_sub(removed);
+    if quota > 0 && added > removed && charged > quota {
+        return false;
+    }
+    *usage = charged;
+    true
+}
+
+fn disk_full() -> bool {
+    CONFIG.disk_quota_bytes > 0 && *DISK_USAGE.lock().unwrap() >= CONFIG.disk_quota_bytes
+}
+
+fn server_full() -> (status::Status, String) {
+    (status::InsufficientStorage, "The server is full, please try again later.\n".to_string())
+}
+
+// Unlike bodyparser::Raw, this doesn't insist on UTF-8, so binary files can be
+// pasted too. Reads at most one byte more than a paste may hold, which is
+// enough for callers to tell that the body is too large.
+fn read_body(req: &mut Request) -> io::Result<Vec<u8>> {
+    read_limited(req, CONFIG.max_paste_bytes as u64)
+}
+
+// Whether the request says up front that its body is larger than the limit, in
+// which case there's no point reading it. Bodies without a Content-Length (i.e.
+// chunked ones) still have to be read to find out, see read_limited().
+fn declared_over(req: &Request, limit: u64) -> bool {
+    req.headers.get::<ContentLength>().map_or(false, |&ContentLength(len)| len > limit)
+}
+
+fn too_large() -> (status::Status, String) {
+    (status::PayloadTooLarge, format!("Pastes may not be more than {} MB.\n", CONFIG.max_paste_mb()))
+}
+
+// Compressed bodies are decompressed as they are read, and it is the
+// decompressed body which is limited, so that a small body can't expand into
+// an enormous one.
+fn read_limited(req: &mut Request, limit: u64) -> io::Result<Vec<u8>> {
+    let encoding = body_encoding(req);
+    decode_limited(&mut req.body, encoding.as_ref().map(|encoding| encoding.as_str()), limit)
+}
+
+fn decode_limited<R: Read>(body: R, encoding: Option<&str>, limit: u64) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    match encoding {
+        Some("gzip") => try!(try!(GzDecoder::new(body)).take(limit + 1).read_to_end(&mut decoded)),
+        Some("deflate") => try!(ZlibDecoder::new(body).take(limit + 1).read_to_end(&mut decoded)),
+        _ => try!(body.take(limit + 1).read_to_end(&mut decoded))
+    };
+    Ok(decoded)
+}
+
+// What to say about a paste read with read_body(), which is more than a paste
+// may hold if it was cut short.
+fn check_paste_size(paste: &[u8]) -> Result<(), (status::Status, String)> {
+    if paste.len() > CONFIG.max_paste_bytes {
+        Err(too_large())
+    } else {
+        Ok(())
+    }
+}
+
+// Bodies which fail to decompress are the client's fault; anything else ours.
+fn body_error(e: io::Error) -> IronError {
+    match e.kind() {
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => {
+            IronError::new(e, (status::BadRequest, "Invalid request: the body could not be decompressed.\n"))
+        },
+        _ => IronError::new(e, status::InternalServerError)
+    }
+}
+
+// JSON can only carry text, so binary pastes have to be sent as they are, as
+// the bodies of raw submissions.
+fn not_utf8() -> (status::Status, &'static str) {
+    (status::BadRequest, "Paste content must be valid UTF-8 (or be submitted as a raw body, which may be binary).\n")
+}
+
+fn is_json(req: &Request) -> bool {
+    match req.headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => true,
+        _ => false
+    }
+}
+
+// Checks a JSON submission, e.g. {"content": "...", "language": "rs", "expires": "1d"},
+// yielding its content and the rest of its fields, or what is wrong with each
+// field that is. "language" is the extension of a language to highlight the
+// paste with by default.
+fn parse_json_submission(body: &[u8]) -> Result<(String, BTreeMap<String, Json>), BTreeMap<String, String>> {
+    check_json_submission(str::from_utf8(body).ok().and_then(|body| Json::from_str(body).ok()))
+}
+
+fn check_json_submission(submission: Option<Json>) -> Result<(String, BTreeMap<String, Json>), BTreeMap<String, String>> {
+    let mut errors = BTreeMap::new();
+    let mut fields = match submission {
+        Some(Json::Object(fields)) => fields,
+        _ => {
+            errors.insert("body".to_string(), "must be a JSON object".to_string());
+            return Err(errors);
+        }
+    };
+    let content = match fields.remove("content") {
+        Some(Json::String(ref content)) if !content.is_empty() => Some(content.clone()),
+        Some(Json::String(_)) => {
+            errors.insert("content".to_string(), "must not be empty".to_string());
+            None
+        },
+        Some(_) => {
+            errors.insert("content".to_string(), "must be a string".to_string());
+            None
+        },
+        None => {
+            errors.insert("content".to_string(), "is required".to_string());
+            None
+        }
+    };
+    for name in &["language", "title", "password", "filename", "custom_id", "namespace", "tags"] {
+        match fields.get(*name) {
+            Some(&Json::String(_)) | None => {},
+            Some(_) => { errors.insert(name.to_string(), "must be a string".to_string()); }
+        }
+    }
+    match fields.get("expires").and_then(json_param) {
+        Some(expires) => if let Err(reason) = parse_ttl(&expires) {
+            errors.insert("expires".to_string(), reason);
+        },
+        None => if fields.contains_key("expires") {
+            errors.insert("expires".to_string(), "must be a string or a number of seconds".to_string());
+        }
+    }
+    if let Some(&Json::String(ref tags)) = fields.get("tags") {
+        if let Err(reason) = parse_tags(tags) {
+            errors.insert("tags".to_string(), reason);
+        }
+    }
+    match fields.get("burn") {
+        Some(&Json::Boolean(_)) | None => {},
+        Some(_) => { errors.insert("burn".to_string(), "must be true or false".to_string()); }
+    }
+    if let Some(&Json::String(ref language)) = fields.get("language") {
+        if !known_language(language) {
+            errors.insert("language".to_string(), format!("\"{}\" is not a language we can highlight", language));
+        }
+    }
+    match content {
+        Some(content) if errors.is_empty() => Ok((content, fields)),
+        _ => Err(errors)
+    }
+}
+
+// The value of a JSON field as submit_param() would have it.
+fn json_param(value: &Json) -> Option<String> {
+    match *value {
+        Json::String(ref value) => Some(value.clone()),
+        Json::Boolean(value) => Some(value.to_string()),
+        Json::I64(value) => Some(value.to_string()),
+        Json::U64(value) => Some(value.to_string()),
+        _ => None
+    }
+}
+
+fn is_multipart(req: &Request) -> bool {
+    match req.headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Multipart, SubLevel::FormData, _))) => true,
+        _ => false
+    }
+}
+
+// Where the options accompanying a paste come from.
+enum Source {
+    // a raw post, whose body is the paste itself
+    Query,
+    // web form submissions carry them as fields
+    Form,
+    // as do JSON submissions, see parse_json_submission()
+    Json(BTreeMap<String, Json>)
+}
+
+fn submit_param(req: &mut Request, name: &str, source: &Source) -> Option<String> {
+    let value = match *source {
+        Source::Query => query_param(req, name),
+        Source::Form => {
+            let params = req.get_ref::<Params>().unwrap();
+            match params.find(&[name]) {
+                Some(&Value::String(ref value)) => Some(value.clone()),
+                _ => None
+            }
+        },
+        Source::Json(ref fields) => fields.get(name).and_then(json_param)
+    };
+    match value {
+        Some(ref v) if v.is_empty() => None,
+        _ => value
+    }
+}
+
+fn query_param(req: &mut Request, name: &str) -> Option<String> {
+    match req.get_ref::<UrlEncodedQuery>() {
+        Ok(query) => query.get(name).and_then(|values| values.first().cloned()),
+        Err(_) => None
+    }
+}
+
+// Accepts a number of seconds, optionally suffixed by a unit: "3600", "90m", "1h", "7d"
+fn parse_ttl(input: &str) -> Result<i64, String> {
+    let input = input.trim();
+    let (count, unit) = match input.chars().last() {
+        Some(c) if c.is_alphabetic() => (&input[..input.len() - c.len_utf8()], c),
+        _ => (input, 's')
+    };
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => DAY_SECS,
+        _ => return Err(format!("unknown time unit \"{}\"", unit))
+    };
+    let ttl = match count.parse::<i64>() {
+        Ok(n) if n > 0 => n.checked_mul(multiplier),
+        _ => None
+    };
+    match ttl {
+        Some(ttl) if ttl <= CONFIG.max_ttl() => Ok(ttl),
+        Some(_) => Err(format!("pastes may not live longer than {} days", CONFIG.paste_days)),
+        None => Err(format!("\"{}\" is not a valid duration", input))
+    }
+}
+
+fn describe_ttl(ttl: i64) -> String {
+    let (count, unit) = if ttl >= DAY_SECS && ttl % DAY_SECS == 0 {
+        (ttl / DAY_SECS, "day")
+    } else if ttl >= 60 * 60 && ttl % (60 * 60) == 0 {
+        (ttl / (60 * 60), "hour")
+    } else if ttl >= 60 && ttl % 60 == 0 {
+        (ttl / 60, "minute")
+    } else {
+        (ttl, "second")
+    };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+// Every ID we hand out (random or custom) passes this, so anything else can be
+// turned away before it gets anywhere near storage, e.g. "../hmac_key.txt".
+fn validate_paste_id(id: &str) -> Result<(), String> {
+    let (namespace, name) = match id.find('/') {
+        Some(i) => (Some(&id[..i]), &id[i + 1..]),
+        None => (None, id)
+    };
+    if name.is_empty() || name.len() > CUSTOM_ID_MAX_LEN || !name.bytes().all(|b| b == b'-' || BASE62.contains(&b))
+        || namespace.map_or(false, |namespace| validate_namespace(namespace).is_err()) {
+        return Err(format!("\"{}\" is not a valid paste ID", id));
+    }
+    Ok(())
+}
+
+// Namespaces share the first part of the path with paste IDs and the routes
+// which aren't for pastes, so the same names are reserved.
+fn validate_namespace(namespace: &str) -> Result<(), String> {
+    if namespace.is_empty() || namespace.len() > NAMESPACE_MAX_LEN {
+        return Err(format!("must be between 1 and {} characters long", NAMESPACE_MAX_LEN));
+    }
+    if !namespace.bytes().all(|b| BASE62.contains(&b)) {
+        return Err("may only contain letters and digits".to_string());
+    }
+    if RESERVED_IDS.contains(&namespace) {
+        return Err(format!("\"{}\" is reserved", namespace));
+    }
+    Ok(())
+}
+
+fn validate_custom_id(id: &str) -> Result<(), String> {
+    if id.len() < CUSTOM_ID_MIN_LEN || id.len() > CUSTOM_ID_MAX_LEN {
+        return Err(format!("must be between {} and {} characters long", CUSTOM_ID_MIN_LEN, CUSTOM_ID_MAX_LEN));
+    }
+    if !id.bytes().all(|b| b == b'-' || BASE62.contains(&b)) {
+        return Err("may only contain letters, digits and hyphens".to_string());
+    }
+    if RESERVED_IDS.contains(&id) {
+        return Err(format!("\"{}\" is reserved", id));
+    }
+    Ok(())
+}
+
+// Tries IDs of the given length until create() doesn't find one already taken,
+// lengthening them by one every other collision so that a crowded server still
+// finds a free ID quickly. The ID is generated by generate(len), so that this
+// can be exercised with IDs chosen to collide.
+fn allocate_id<G, C>(id_len: usize, mut generate: G, mut create: C) -> io::Result<String>
+    where G: FnMut(usize) -> String, C: FnMut(&str) -> io::Result<()>
+{
+    let mut double_id_len = id_len * 2; // so we increase by 1 every two loops
+    loop {
+        let id = generate(double_id_len / 2);
+        match create(&id) {
+            Ok(()) => return Ok(id),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => double_id_len += 1,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+// IDs come straight from the operating system's cryptographically secure RNG,
+// and gen_range() samples without modulo bias, so every character of an ID is
+// equally likely and IDs can't be enumerated. The alphabet is normally
+// CONFIG.id_alphabet, which is checked to be ASCII.
+fn generate_id(size: usize, alphabet: &[u8]) -> String {
+    let mut id = String::with_capacity(size);
+    let mut rng = OsRng::new().expect("opening the OS random number generator");
+    for _ in 0..size {
+        id.push(alphabet[rng.gen_range(0, alphabet.len())] as char);
+    }
+    id
+}
+
+fn gen_key(input: &str) -> String {
+    hmac_hex(input, CONFIG.key_bytes)
+}
+
+// An edit key which, unlike gen_key(), has nothing to do with the paste's ID.
+fn random_key() -> String {
+    let mut rng = OsRng::new().expect("opening the OS random number generator");
+    (0..CONFIG.key_bytes).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
+// What is stored in place of a random edit key, as "<salt>$<hash>".
+fn key_hash(key: &str, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(salt);
+    hasher.input_str(key);
+    format!("{}${}", salt, hasher.result_str())
+}
+
+// The paste ID is mixed in so that pastes sharing a password don't share a hash.
+fn password_hash(id: &str, password: &str) -> String {
+    hmac_hex(&format!("password:{}:{}", id, password), 32)
+}
+
+fn hmac_hex(input: &str, bytes: usize) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), HMAC_KEY.as_bytes());
+    hmac.input(input.as_bytes());
+    let hmac_result = hmac.result();
+    let key: String = hmac_result.code().iter()
+        .take(bytes)
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    key.to_lowercase()
+}
+
+// API tokens are chosen by their users, so all we can do is insist that they're
+// long enough not to be guessed.
+fn request_token(req: &Request) -> Result<Option<String>, String> {
+    let value = match req.headers.get_raw("Authorization").and_then(|values| values.first()) {
+        Some(value) => value,
+        None => return Ok(None)
+    };
+    let value = try!(str::from_utf8(value).map_err(|_| "not UTF-8".to_string()));
+    if !value.starts_with("Bearer ") {
+        return Err("only \"Authorization: Bearer <token>\" is supported".to_string());
+    }
+    let token = value["Bearer ".len()..].trim();
+    if token.len() < TOKEN_MIN_LEN {
+        return Err(format!("must be at least {} characters long", TOKEN_MIN_LEN));
+    }
+    Ok(Some(token.to_string()))
+}
+
+// Only this is stored, so the tokens themselves can't be read back out of the metadata.
+fn token_hash(token: &str) -> String {
+    hmac_hex(&format!("token:{}", token), 32)
+}
+
+// Likewise IP addresses, which are only needed to count each one's pastes.
+fn submitter_hash(ip: &str) -> String {
+    hmac_hex(&format!("ip:{}", ip), 32)
+}
+
+// Raw bodies don't carry the name of the file they came from, so clients such
+// as curl may give it in a header instead of the "filename" query parameter.
+fn request_filename(req: &Request) -> Option<String> {
+    req.headers.get_raw("X-Filename")
+        .and_then(|values| values.first())
+        .and_then(|value| String::from_utf8(value.clone()).ok())
+}
+
+// View passwords may be supplied either as a query parameter or a header.
+fn request_password(req: &mut Request) -> Option<String> {
+    if let Some(values) = req.headers.get_raw("X-Paste-Password") {
+        if let Some(value) = values.first() {
+            return String::from_utf8(value.clone()).ok();
+        }
+    }
+    query_param(req, "password")
+}
+
+fn get_hostname(req: &Request) -> String {
+    match req.headers.get::<Host>() {
+        Some(h) => {
+            let mut host = h.hostname.to_owned();
+            if let Some(port) = h.port {
+                host = host + ":" + &port.to_string();
+            }
+            return host
+        },
+        _ => CONFIG.socket.clone()
+    }
+}
+
+// The scheme and host the client used to reach us, as the start of the URLs we
+// hand out, according to the proxy in front of us if there is one.
+fn base_url(req: &Request) -> String {
+    let scheme = match forwarded_header(req, "X-Forwarded-Proto") {
+        Some(ref proto) if proto == "http" || proto == "https" => proto.clone(),
+        _ => req.url.scheme().to_string()
+    };
+    let host = match forwarded_header(req, "X-Forwarded-Host") {
+        Some(host) => host,
+        None => get_hostname(req)
+    };
+    format!("{}://{}", scheme, host)
+}
+
+// The first (client-most) value of a header which each proxy may append to.
+fn forwarded_header(req: &Request, name: &str) -> Option<String> {
+    req.headers.get_raw(name)
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim())
+        .and_then(|value| if value.is_empty() { None } else { Some(value.to_string()) })
+}
+
+// The view and edit URLs of a paste, as given to whoever submitted it.
+fn paste_urls(req: &Request, id: &str, key: &str) -> (String, String) {
+    let view_url = format!("{base_url}/{id}", base_url = base_url(req), id = id);
+    let edit_url = format!("{view_url}/{key}", view_url = view_url, key = key);
+    (view_url, edit_url)
+}
+
+fn wants_json(req: &Request) -> bool {
+    match req.headers.get::<Accept>() {
+        Some(&Accept(ref items)) => items.iter().any(|item| match item.item {
+            Mime(TopLevel::Application, SubLevel::Json, _) => true,
+            _ => false
+        }),
+        None => false
+    }
+}
+
+// Also returns the ETag of the paste, since we have to read it anyway.
+fn paste_info(id: &str, meta: Option<&PasteMeta>) -> io::Result<(Json, EntityTag)> {
+    let paste = match try!(STORAGE.load(id)) {
+        Some(paste) => paste,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "no such paste"))
+    };
+    let size = paste.len();
+    let (created, expires) = try!(paste_times(id, meta));
+    let mut info = BTreeMap::new();
+    info.insert("id".to_string(), id.to_json());
+    info.insert("size".to_string(), size.to_json());
+    info.insert("created".to_string(), UTC.timestamp(created, 0).to_rfc3339().to_json());
+    info.insert("expires".to_string(), UTC.timestamp(expires, 0).to_rfc3339().to_json());
+    if let Some(title) = meta.and_then(|meta| meta.title.as_ref()) {
+        info.insert("title".to_string(), title.to_json());
+    }
+    info.insert("password_protected".to_string(), meta.map_or(false, |meta| meta.password.is_some()).to_json());
+    if let Some(meta) = meta {
+        info.insert("views".to_string(), meta.views.to_json());
+    }
+    if let Some(language) = meta.and_then(default_language) {
+        info.insert("language".to_string(), language.to_json());
+    }
+    if let Some(meta) = meta {
+        if !meta.tags.is_empty() {
+            info.insert("tags".to_string(), meta.tags.to_json());
+        }
+    }
+    Ok((Json::Object(info), content_etag(&paste)))
+}
+
+// When the paste was last modified, and when it will be deleted. The latter is
+// worked out just as the cleanup thread does (see Storage::list_expired()).
+fn paste_times(id: &str, meta: Option<&PasteMeta>) -> io::Result<(i64, i64)> {
+    let modified = try!(STORAGE.modified(id));
+    let expires = meta.map_or(modified + CONFIG.max_ttl(), |meta| meta.expires);
+    Ok((modified, expires))
+}
+
+fn set_timestamps(resp: &mut Response, (created, expires): (i64, i64)) {
+    let last_modified = UTC.timestamp(created, 0).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    resp.headers.set_raw("Last-Modified", vec![last_modified.into_bytes()]);
+    resp.headers.set_raw("X-Created-At", vec![UTC.timestamp(created, 0).to_rfc3339().into_bytes()]);
+    resp.headers.set_raw("X-Expires-At", vec![UTC.timestamp(expires, 0).to_rfc3339().into_bytes()]);
+}
+
+fn content_etag(paste: &[u8]) -> EntityTag {
+    EntityTag::new(false, content_hash(paste))
+}
+
+fn content_hash(paste: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(paste);
+    hasher.result_str()
+}
+
+fn etag_matches(req: &Request, etag: &EntityTag) -> bool {
+    match req.headers.get::<IfNoneMatch>() {
+        Some(&IfNoneMatch::Any) => true,
+        Some(&IfNoneMatch::Items(ref items)) => items.iter().any(|item| item.weak_eq(etag)),
+        None => false
+    }
+}
+
+// Whether the paste, whose ETag is None if it doesn't exist, is the one an
+// If-Match header (if any) asks to be changed. Unlike If-None-Match, this
+// compares ETags strongly.
+fn if_match(req: &Request, etag: Option<&EntityTag>) -> bool {
+    matches_if_match(req.headers.get::<IfMatch>(), etag)
+}
+
+fn matches_if_match(header: Option<&IfMatch>, etag: Option<&EntityTag>) -> bool {
+    match (header, etag) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(&IfMatch::Any), Some(_)) => true,
+        (Some(&IfMatch::Items(ref items)), Some(etag)) => items.iter().any(|item| item.strong_eq(etag))
+    }
+}
+
+fn precondition_failed(id: &str) -> Response {
+    Response::with((status::PreconditionFailed,
+                    format!("Paste {} has changed since you read it; fetch it again and retry.\n", id)))
+}
+
+// Only consulted without an If-None-Match header, which takes precedence.
+fn unmodified_since(req: &Request, modified: i64) -> bool {
+    if req.headers.has::<IfNoneMatch>() {
+        return false;
+    }
+    match req.headers.get::<IfModifiedSince>() {
+        Some(&IfModifiedSince(HttpDate(ref since))) => modified <= since.to_timespec().sec,
+        None => false
+    }
+}
+
+// Whether the terminal view should be coloured: always, unless the query
+// parameter "color" is "never" (or "false"), or is "auto" or missing and the
+// request says it can't or doesn't want colour. Since curl doesn't pass on the
+// environment, that's up to the user, with an X-No-Color header (cf. NO_COLOR)
+// or an "X-Term: dumb" one.
+fn wants_color(req: &mut Request) -> bool {
+    match query_param(req, "color").as_ref().map(|color| color.as_str()) {
+        Some("always") | Some("true") => true,
+        Some("never") | Some("false") => false,
+        _ => {
+            let no_color = req.headers.get_raw("X-No-Color").map_or(false, |values| !values.is_empty());
+            let dumb = req.headers.get_raw("X-Term")
+                .and_then(|values| values.first())
+                .map_or(false, |term| &term[..] == b"dumb");
+            !no_color && !dumb
+        }
+    }
+}
+
+fn is_curl(req: &Request) -> bool {
+    match req.headers.get::<UserAgent>() {
+        Some(&UserAgent(ref string)) => string.starts_with("curl/"),
+        _ => true
+    }
+}
+
+// Accepts "10-20", "L10-L20" or a single line "10", and clamps the result to
+// the lines which actually exist.
+fn parse_line_range(input: &str, line_count: usize) -> Option<(usize, usize)> {
+    if line_count == 0 {
+        return None;
+    }
+    let mut bounds = input.splitn(2, '-').map(|bound| bound.trim().trim_left_matches('L').parse::<usize>());
+    let first = match bounds.next() {
+        Some(Ok(n)) => n,
+        _ => return None
+    };
+    let last = match bounds.next() {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => return None,
+        None => first
+    };
+    let clamp = |n: usize| std::cmp::max(1, std::cmp::min(n, line_count));
+    let (first, last) = (clamp(first), clamp(last));
+    if first <= last { Some((first, last)) } else { Some((last, first)) }
+}
+
+// Unknown themes fall back to the default; the flag tells the caller whether that happened.
+// Without a theme asked for, the one configured for the language is used, if
+// there is one (and it exists).
+fn find_theme(name: Option<String>, lang: &str) -> (&'static Theme, bool) {
+    let default = &THEME_SET.themes[DEFAULT_THEME];
+    match name {
+        Some(name) => match THEME_SET.themes.get(&name) {
+            Some(theme) => (theme, true),
+            None => (default, false)
+        },
+        None => match CONFIG.language_themes.get(lang).and_then(|name| THEME_SET.themes.get(name)) {
+            Some(theme) => (theme, true),
+            None => (default, true)
+        }
+    }
+}
+
+// The HTML view sets the background once for the whole paste rather than per line.
+fn theme_background(theme: &Theme) -> String {
+    match theme.settings.background {
+        Some(c) => format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b),
+        None => "#2d2d2d".to_string()
+    }
+}
+
+// Only the final path component is kept, and nothing which could break up
+// the line-based metadata file.
+fn clean_filename(input: &str) -> Option<String> {
+    let name: String = input.rsplit(|c| c == '/' || c == '\\').next().unwrap_or("")
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(255)
+        .collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+// Tags are given comma-separated, and are lowercased so that "Rust" and "rust"
+// are one and the same. Repeats are dropped.
+fn parse_tags(input: &str) -> Result<Vec<String>, String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in input.split(',') {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tags.contains(&tag) {
+            continue;
+        }
+        try!(validate_tag(&tag));
+        tags.push(tag);
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(format!("there may be at most {}", MAX_TAGS));
+    }
+    Ok(tags)
+}
+
+fn validate_tag(tag: &str) -> Result<(), String> {
+    if tag.is_empty() || tag.len() > TAG_MAX_LEN {
+        return Err(format!("each must be between 1 and {} characters long", TAG_MAX_LEN));
+    }
+    if !tag.bytes().all(|b| b == b'-' || (b >= b'a' && b <= b'z') || (b >= b'0' && b <= b'9')) {
+        return Err(format!("\"{}\" may only contain letters, digits and hyphens", tag));
+    }
+    Ok(())
+}
+
+// Titles are kept to a single line of reasonable length.
+fn clean_title(input: &str) -> Option<String> {
+    let title: String = input.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(TITLE_MAX_LEN)
+        .collect();
+    let title = title.trim();
+    if title.is_empty() { None } else { Some(title.to_string()) }
+}
+
+// The language a paste is highlighted with unless another is asked for: the
+// one it was submitted with, or else the one its filename suggests.
+fn default_language(meta: &PasteMeta) -> Option<String> {
+    meta.language.clone().or_else(|| meta.filename.as_ref().and_then(|filename| filename_language(filename)))
+}
+
+// Pastes are served as they are to curl and the like, and highlighted with
+// their default language for browsers, unless the query parameter "highlight"
+// says otherwise ("auto" or "false").
+fn highlight_by_default(req: &mut Request) -> bool {
+    match query_param(req, "highlight") {
+        Some(ref highlight) if highlight == "auto" => true,
+        Some(ref highlight) if highlight == "false" => false,
+        _ => accepts_html(req)
+    }
+}
+
+fn accepts_html(req: &Request) -> bool {
+    match req.headers.get::<Accept>() {
+        Some(&Accept(ref items)) => items.iter().any(|item| match item.item {
+            Mime(TopLevel::Text, SubLevel::Html, _) => true,
+            _ => false
+        }),
+        None => false
+    }
+}
+
+// Whether the extension is one we can highlight, exactly as it is given.
+fn known_language(ext: &str) -> bool {
+    filename_language(&format!("paste.{}", ext)).as_ref().map(|known| known.as_str()) == Some(ext)
+}
+
+// The extension of the filename, if it is one we can highlight, lowercased if
+// that's what it takes (e.g. for "MAIN.RS"). Names without an extension are
+// tried whole, since some syntaxes claim names such as "Makefile".
+fn filename_language(filename: &str) -> Option<String> {
+    let path = Path::new(filename);
+    let ext = match path.extension().or_else(|| path.file_name()).and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.to_string(),
+        None => return None
+    };
+    let known = with_syntax_set(move |ss| {
+        let lowercase = ext.to_lowercase();
+        vec![ext, lowercase].into_iter().find(|ext| match ss.find_syntax_by_extension(ext) {
+            Some(syntax) => syntax.name != "Plain Text",
+            None => false
+        })
+    });
+    known
+}
+
+// Returns the name of the detected syntax and an extension which selects it.
+fn detect_language(buffer: &str) -> Option<(String, String)> {
+    let first_line = buffer.lines().next().unwrap_or("").to_string();
+    with_syntax_set(move |ss| {
+        ss.find_syntax_by_first_line(&first_line)
+            .and_then(|syntax| syntax.file_extensions.first().map(|ext| (syntax.name.clone(), ext.clone())))
+    })
+}
+
+// What every paste_html page gets. The title is inserted with {{title}}, which
+// handlebars escapes.
+fn page_data(id: &str, title: Option<&String>) -> BTreeMap<String, Json> {
+    let mut data = BTreeMap::new();
+    data.insert("id".to_string(), id.to_json());
+    if let Some(title) = title {
+        data.insert("title".to_string(), title.to_json());
+    }
+    data
+}
+
+// Raw HTML in the markdown is shown as text rather than passed through, and
+// links may only point somewhere harmless, so that pastes can't run scripts in
+// the reader's browser.
+fn render_markdown(buffer: &str) -> String {
+    let events = Parser::new(buffer).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        Event::Start(Tag::Link(url, title)) => Event::Start(Tag::Link(safe_url(url), title)),
+        Event::Start(Tag::Image(url, title)) => Event::Start(Tag::Image(safe_url(url), title)),
+        event => event
+    });
+    let mut html = String::new();
+    push_html(&mut html, events);
+    html
+}
+
+// Relative URLs are fine; absolute ones need a scheme which can't run anything.
+fn safe_url(url: Cow<str>) -> Cow<str> {
+    let scheme = match url.find(|c| c == ':' || c == '/' || c == '?' || c == '#') {
+        Some(i) if url[i..].starts_with(':') => url[..i].trim().to_lowercase(),
+        _ => return url
+    };
+    match scheme.as_str() {
+        "http" | "https" | "mailto" => url,
+        _ => Cow::Borrowed("#")
+    }
+}
+
+// The paste_html template inserts each line as it is, so this is where the
+// paste's contents get escaped. Everything else in the template is escaped by
+// handlebars itself, apart from the rendered markdown (see render_markdown()).
+fn styles_to_html(ranges: &[(Style, &str)]) -> String {
+    let mut html = String::new();
+    for &(ref style, text) in ranges {
+        let c = style.foreground;
+        html.push_str(&format!("<span style=\"color:#{:02x}{:02x}{:02x};", c.r, c.g, c.b));
+        if style.font_style.contains(FONT_STYLE_BOLD) {
+            html.push_str("font-weight:bold;");
+        }
+        if style.font_style.contains(FONT_STYLE_ITALIC) {
+            html.push_str("font-style:italic;");
+        }
+        if style.font_style.contains(FONT_STYLE_UNDERLINE) {
+            html.push_str("text-decoration:underline;");
+        }
+        html.push_str("\">");
+        html.push_str(&escape_html(text));
+        html.push_str("</span>");
+    }
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+// Says that there's no language with the extension, suggesting the extensions
+// which were most likely meant: those of languages named e.g. "rust", and those
+// within a typo or two of it.
+fn unknown_language(ss: &SyntaxSet, lang: &str) -> String {
+    let wanted = lang.to_lowercase();
+    let mut candidates: Vec<(usize, &str)> = Vec::new();
+    for syntax in ss.syntaxes().iter().filter(|syntax| !syntax.hidden && syntax.name != "Plain Text") {
+        if let Some(extension) = syntax.file_extensions.first() {
+            if syntax.name.to_lowercase() == wanted {
+                candidates.push((0, &extension[..]));
+            }
+        }
+        for extension in &syntax.file_extensions {
+            let distance = edit_distance(&wanted, &extension.to_lowercase());
+            // up to half of it may be wrong, so that short ones aren't suggested for everything
+            if distance <= std::cmp::min(2, extension.len() / 2) {
+                candidates.push((distance, &extension[..]));
+            }
+        }
+    }
+    candidates.sort();
+    let mut suggestions: Vec<&str> = Vec::new();
+    for (_, extension) in candidates {
+        if !suggestions.contains(&extension) && suggestions.len() < MAX_SUGGESTIONS {
+            suggestions.push(extension);
+        }
+    }
+    if suggestions.is_empty() {
+        format!("Requested highlight \"{}\" not available (see /languages)", lang)
+    } else {
+        format!("Requested highlight \"{}\" not available; did you mean {}? (see /languages)", lang, suggestions.join(", "))
+    }
+}
+
+// The Levenshtein distance between two strings, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current.push(std::cmp::min(substitution, std::cmp::min(previous[j + 1], current[j]) + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// As highlight(), but leaves the styled text as it is.
+fn highlight_ranges(buffer: &str, lang: &str, theme: &Theme) -> Result<Vec<Vec<(Style, String)>>, String> {
+    let (buffer, lang, theme) = (buffer.to_string(), lang.to_string(), theme.clone());
+    with_syntax_set(move |ss| {
+        let syntax = ss.find_syntax_by_extension(&lang).unwrap_or_else(|| ss.find_syntax_plain_text());
+        if syntax.name == "Plain Text" {
+            return Err(unknown_language(ss, &lang));
+        }
+        let mut highlighter = HighlightLines::new(syntax, &theme);
+        Ok(buffer.lines()
+            .map(|line| highlighter.highlight(line).into_iter().map(|(style, text)| (style, text.to_string())).collect())
+            .collect())
+    })
+}
+
+// Whether highlight() would accept the language, without highlighting anything.
+fn check_language(lang: &str) -> Result<(), String> {
+    let lang = lang.to_string();
+    with_syntax_set(move |ss| {
+        match ss.find_syntax_by_extension(&lang) {
+            Some(syntax) if syntax.name != "Plain Text" => Ok(()),
+            _ => Err(unknown_language(ss, &lang))
+        }
+    })
+}
+
+fn highlight(buffer: String, lang: &str, html: bool, theme: &Theme) -> HighlightedText {
+    let (lang, theme) = (lang.to_string(), theme.clone());
+    with_syntax_set(move |ss| {
+        let syntax = ss.find_syntax_by_extension(&lang).unwrap_or_else(|| ss.find_syntax_plain_text());
+        if syntax.name == "Plain Text" {
+            return HighlightedText::Error(unknown_language(ss, &lang));
+        }
+        if html {
+            let mut highlighter = HighlightLines::new(syntax, &theme);
+            let lines = buffer.lines()
+                .map(|line| styles_to_html(&highlighter.highlight(line)[..]))
+                .collect();
+            HighlightedText::Html(lines)
+        } else {
+            let mut highlighter = HighlightLines::new(syntax, &theme);
+            let mut output = String::new();
             for line in buffer.lines() {
                 let ranges: Vec<(Style, &str)> = highlighter.highlight(line);
-                let escaped;
-                escaped = as_24_bit_terminal_escaped(&ranges[..], false);
-                output += &format!("{}\n", escaped);
+                // blank lines need no escapes at all
+                if ranges.iter().all(|&(_, text)| text.is_empty()) {
+                    output.push('\n');
+                    continue;
+                }
+                let escaped = as_24_bit_terminal_escaped(&ranges[..], false);
+                // reset the colours, so they don't carry on past the paste
+                output += &format!("{}\x1b[0m\n", escaped);
             }
             HighlightedText::Terminal(output)
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use syntect::highlighting::FontStyle;
+
+    #[test]
+    fn generate_id_uses_only_the_alphabet() {
+        for alphabet in &["ab", "0123456789", "xyzXYZ"] {
+            let id = generate_id(200, alphabet.as_bytes());
+            assert_eq!(id.len(), 200);
+            assert!(id.chars().all(|c| alphabet.contains(c)), "{} isn't made of {}", id, alphabet);
+        }
+    }
+
+    #[test]
+    fn generate_id_is_uniform() {
+        // 10000 of each expected, where chance alone strays by about 100
+        let alphabet = b"0123456789abcdef";
+        let mut counts = HashMap::new();
+        for c in generate_id(160000, alphabet).chars() {
+            *counts.entry(c).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), alphabet.len());
+        for (c, count) in counts {
+            assert!(count > 9000 && count < 11000, "{} came up {} times", c, count);
+        }
+    }
+
+    #[test]
+    fn allocate_id_grows_every_other_collision() {
+        let mut lengths = Vec::new();
+        let mut collisions = 0;
+        let id = allocate_id(5, |len| { lengths.push(len); "x".repeat(len) }, |_| {
+            if collisions < 6 {
+                collisions += 1;
+                Err(io::Error::new(io::ErrorKind::AlreadyExists, "taken"))
+            } else {
+                Ok(())
+            }
+        }).unwrap();
+        assert_eq!(lengths, vec![5, 5, 6, 6, 7, 7, 8]);
+        assert_eq!(id, "xxxxxxxx");
+    }
+
+    #[test]
+    fn allocate_id_gives_up_on_other_errors() {
+        let mut attempts = 0;
+        let result = allocate_id(5, |len| "x".repeat(len), |_| {
+            attempts += 1;
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only"))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(attempts, 1);
+    }
+
+    const SCRIPT: &'static str = "<script>alert('\"&')</script>";
+    const ESCAPED_SCRIPT: &'static str = "&lt;script&gt;alert(&#39;&quot;&amp;&#39;)&lt;/script&gt;";
+
+    fn plain_style() -> Style {
+        let black = Color { r: 0, g: 0, b: 0, a: 0xff };
+        Style { foreground: black, background: black, font_style: FontStyle::empty() }
+    }
+
+    #[test]
+    fn escape_html_escapes_markup() {
+        assert_eq!(escape_html(SCRIPT), ESCAPED_SCRIPT);
+        assert_eq!(escape_html("plain text"), "plain text");
+    }
+
+    #[test]
+    fn styles_to_html_escapes_the_text() {
+        let html = styles_to_html(&[(plain_style(), SCRIPT)]);
+        assert_eq!(html, format!("<span style=\"color:#000000;\">{}</span>", ESCAPED_SCRIPT));
+    }
+
+    #[test]
+    fn paste_html_leaves_scripts_escaped() {
+        // the lines go through {{{html}}}, which doesn't escape them again
+        let lines = vec![HtmlLine { number: 1, html: styles_to_html(&[(plain_style(), SCRIPT)]), selected: false }];
+        let mut data = page_data("abcde", Some(&SCRIPT.to_string()));
+        data.insert("lines".to_string(), lines.to_json());
+        data.insert("background".to_string(), "#ffffff".to_json());
+        let page = templates().registry.read().unwrap().render("paste_html", &data).unwrap();
+        assert!(!page.contains("<script>"), "{}", page);
+        assert!(page.contains(ESCAPED_SCRIPT), "{}", page);
+    }
+
+    #[test]
+    fn validate_paste_id_rejects_paths() {
+        for id in &["../hmac_key.txt", "..", ".", "abc/../def", "/etc/passwd", "a/b/c", "abc.def", "abc\\def", ""] {
+            assert!(validate_paste_id(id).is_err(), "{:?} was accepted", id);
+        }
+        assert!(validate_paste_id("abcde").is_ok());
+        assert!(validate_paste_id("team/abcde").is_ok());
+    }
+
+    #[test]
+    fn clean_filename_drops_directories() {
+        assert_eq!(clean_filename("../hmac_key.txt"), Some("hmac_key.txt".to_string()));
+        assert_eq!(clean_filename("..\\..\\config.toml"), Some("config.toml".to_string()));
+        assert_eq!(clean_filename("/etc/passwd"), Some("passwd".to_string()));
+        for name in &["..", ".", "../", "dir/..", "", "\u{0}"] {
+            assert_eq!(clean_filename(name), None, "{:?} was kept", name);
+        }
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_too_large() {
+        let limit = CONFIG.max_paste_bytes;
+        let mut gzipped = GzEncoder::new(Vec::new(), Compression::Default);
+        gzipped.write_all(&vec![b'x'; limit + 1]).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+        // the limit is on the decompressed body, however small the compressed one is
+        assert!(gzipped.len() < limit);
+        let bodies = vec![(vec![b'x'; limit + 1], None), (gzipped, Some("gzip"))];
+        for (body, encoding) in bodies {
+            let paste = decode_limited(&body[..], encoding, limit as u64).unwrap();
+            assert_eq!(paste.len(), limit + 1);
+            assert_eq!(check_paste_size(&paste).unwrap_err().0, status::PayloadTooLarge);
+        }
+        let paste = decode_limited(&vec![b'x'; limit][..], None, limit as u64).unwrap();
+        assert!(check_paste_size(&paste).is_ok());
+    }
+
+    #[test]
+    fn info_pages_fill_in_the_configuration() {
+        let base_url = "https://paste.example";
+        let hbse = templates();
+        for template in &["index", "help"] {
+            let data = info_page_data(base_url.to_string());
+            assert_eq!(info_page(template, data.clone()).status, Some(status::Ok));
+            let page = hbse.registry.read().unwrap().render(template, &data).unwrap();
+            assert!(!page.contains("{{"), "{} has placeholders left:\n{}", template, page);
+            assert!(page.contains(&format!("{}/{}", base_url, CONFIG.example_id)), "{}", page);
+        }
+        let help = hbse.registry.read().unwrap().render("help", &info_page_data(base_url.to_string())).unwrap();
+        assert!(help.contains(&format!("at most {} days", CONFIG.paste_days)), "{}", help);
+        assert!(help.contains(&format!("larger than {} MB", CONFIG.max_paste_mb())), "{}", help);
+    }
+
+    #[test]
+    fn stale_if_match_is_refused() {
+        let read = IfMatch::Items(vec![content_etag(b"what the client read")]);
+        let current = content_etag(b"what somebody replaced it with since");
+        assert!(!matches_if_match(Some(&read), Some(&current)));
+        assert_eq!(precondition_failed("abcde").status, Some(status::PreconditionFailed));
+
+        assert!(matches_if_match(Some(&read), Some(&content_etag(b"what the client read"))));
+        assert!(matches_if_match(None, Some(&current)));
+        assert!(matches_if_match(Some(&IfMatch::Any), Some(&current)));
+        // there's nothing to match once the paste has gone
+        assert!(!matches_if_match(Some(&IfMatch::Any), None));
+        assert!(!matches_if_match(Some(&read), None));
+        // weak ETags never match strongly
+        let weak = IfMatch::Items(vec![EntityTag::new(true, current.tag().to_string())]);
+        assert!(!matches_if_match(Some(&weak), Some(&current)));
+    }
+
+    #[test]
+    fn shared_paste_keys_only_delete_their_own_share() {
+        let id = "abcde";
+        let original = gen_key(id);
+        let other = random_key();
+        let mut meta = PasteMeta::new(60);
+        meta.refs = 2;
+        meta.ref_keys.push(key_hash(&other, &random_key()));
+
+        assert!(release_reference(id, &original, &mut meta));
+        assert_eq!(meta.refs, 1);
+        // deleting again with the same key doesn't take the other submitter's share
+        assert!(!release_reference(id, &original, &mut meta));
+        assert_eq!(meta.refs, 1);
+        assert!(!key_valid(id, &original, Some(&meta)));
+        assert_eq!(key_holder(id, &other, Some(&meta)), Some(KeyHolder::Reference(0)));
+
+        assert!(release_reference(id, &other, &mut meta));
+        assert_eq!(meta.refs, 0);
+        assert!(meta.ref_keys.is_empty());
+        assert!(!key_valid(id, &other, Some(&meta)));
+    }
+
+    #[test]
+    fn concurrent_submissions_stay_under_the_cap() {
+        let stored = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8).map(|_| {
+            let stored = stored.clone();
+            thread::spawn(move || {
+                match check_paste_cap(3, || Ok(stored.load(Ordering::SeqCst) as u64)) {
+                    Ok(_capped) => {
+                        // as slow as storing a paste, so that the others catch up
+                        thread::sleep(time::Duration::from_millis(20));
+                        stored.fetch_add(1, Ordering::SeqCst);
+                        true
+                    },
+                    Err((code, _)) => {
+                        assert_eq!(code, status::Forbidden);
+                        false
+                    }
+                }
+            })
+        }).collect();
+        let admitted = threads.into_iter().map(|thread| thread.join().unwrap()).filter(|&admitted| admitted).count();
+        assert_eq!(admitted, 3);
+        assert_eq!(stored.load(Ordering::SeqCst), 3);
+        assert!(check_paste_cap(0, || Ok(1000)).unwrap().is_none());
+    }
+
+    fn body_of(resp: &mut Response) -> Vec<u8> {
+        let mut body = Vec::new();
+        if let Some(mut writer) = resp.body.take() {
+            writer.write_body(&mut ResponseBody::new(&mut body)).unwrap();
+        }
+        body
+    }
+
+    #[test]
+    fn head_responses_keep_the_length_of_their_body() {
+        let page = "<pre>highlighted</pre>";
+        let mut resp = Response::with((status::Ok, page));
+        drop_body(&mut resp).unwrap();
+        assert_eq!(resp.headers.get::<ContentLength>(), Some(&ContentLength(page.len() as u64)));
+        assert!(resp.body.is_some());
+        assert!(body_of(&mut resp).is_empty());
+
+        // e.g. streamed raw pastes, which set their own
+        let mut resp = Response::with(status::Ok);
+        resp.headers.set(ContentLength(1234));
+        drop_body(&mut resp).unwrap();
+        assert_eq!(resp.headers.get::<ContentLength>(), Some(&ContentLength(1234)));
+        assert!(resp.body.is_some());
+
+        let mut resp = Response::with(status::NotFound);
+        drop_body(&mut resp).unwrap();
+        assert_eq!(resp.headers.get::<ContentLength>(), Some(&ContentLength(0)));
+    }
+
+    #[test]
+    fn disk_usage_is_charged_up_to_the_quota() {
+        let usage = Mutex::new(900);
+        assert!(!charge_usage(&usage, 1000, 101, 0));
+        assert_eq!(*usage.lock().unwrap(), 900);
+        assert!(charge_usage(&usage, 1000, 100, 0));
+        assert_eq!(*usage.lock().unwrap(), 1000);
+        // shrinking is never refused, nor is anything without a quota
+        assert!(charge_usage(&usage, 1000, 50, 80));
+        assert_eq!(*usage.lock().unwrap(), 970);
+        assert!(charge_usage(&usage, 0, 5000, 0));
+
+        // concurrent writes can't all fit in the same space
+        let usage = Arc::new(Mutex::new(0));
+        let threads: Vec<_> = (0..10).map(|_| {
+            let usage = usage.clone();
+            thread::spawn(move || charge_usage(&usage, 1000, 300, 0))
+        }).collect();
+        let charged = threads.into_iter().map(|thread| thread.join().unwrap()).filter(|&charged| charged).count();
+        assert_eq!(charged, 3);
+        assert_eq!(*usage.lock().unwrap(), 900);
+    }
+
+    #[test]
+    fn credentials_are_checked_against_their_hash() {
+        let hash = credential_hash("hunter2", "0123456789abcdef", 1000);
+        assert!(hash.starts_with("pbkdf2-sha256$1000$0123456789abcdef$"), "{}", hash);
+        assert!(credential_matches("hunter2", &hash));
+        assert!(!credential_matches("hunter3", &hash));
+        assert!(!credential_matches("hunter2", &hash.replace("$1000$", "$999$")));
+        // as --hash-password printed them before
+        assert!(!credential_matches("hunter2", &key_hash("hunter2", "0123456789abcdef")));
+    }
+
+    #[test]
+    fn configuration_is_printed_without_password_hashes() {
+        let mut config = Config::load("does-not-exist.toml").unwrap();
+        config.auth_user = Some("team".to_string());
+        config.auth_password_hash = Some(credential_hash("hunter2", "salt", 1));
+        config.admin_password_hash = Some(credential_hash("admin2", "salt", 1));
+        let printed = format!("{:?}", config);
+        assert!(printed.contains("\"team\""), "{}", printed);
+        assert!(!printed.contains(PASSWORD_HASH_SCHEME), "{}", printed);
+    }
+}
@@ -0,0 +1,53 @@
+use chrono::UTC;
+
+// Everything we know about a paste besides its contents.
+#[derive(Debug, Clone)]
+pub struct PasteMeta {
+    pub expires: i64, // unix timestamp
+    pub ttl: i64,     // seconds, so that replacing a paste can renew its lifetime
+    pub burn: bool,   // delete the paste once it has been viewed
+    pub password: Option<String>, // see password_hash()
+    pub filename: Option<String>, // as given at submission, see clean_filename()
+    pub title: Option<String>, // see clean_title()
+    pub language: Option<String>, // the extension to highlight it with by default, see default_language()
+    pub views: u64,
+    pub hash: Option<String>, // of the contents, for deduplication; cleared when they change
+    pub refs: u64, // how many submissions share the paste, see deduplicate()
+    pub key_hash: Option<String>, // for pastes with random edit keys, see key_hash()
+    pub ref_keys: Vec<String>, // the hashed keys of the submissions sharing the paste, see deduplicate()
+    pub owner: Option<String>, // the hash of the API token it was submitted with, see token_hash()
+    pub submitter: Option<String>, // the hash of the IP address it was submitted from, see submitter_hash()
+    pub tags: Vec<String>, // see parse_tags()
+    pub renewals: u32 // how many times its lifetime has been extended, see renew_paste()
+}
+
+impl PasteMeta {
+    pub fn new(ttl: i64) -> PasteMeta {
+        PasteMeta {
+            expires: UTC::now().timestamp() + ttl,
+            ttl: ttl,
+            burn: false,
+            password: None,
+            filename: None,
+            title: None,
+            language: None,
+            views: 0,
+            hash: None,
+            refs: 1,
+            key_hash: None,
+            ref_keys: Vec::new(),
+            owner: None,
+            submitter: None,
+            tags: Vec::new(),
+            renewals: 0
+        }
+    }
+
+    pub fn renew(&mut self) {
+        self.expires = UTC::now().timestamp() + self.ttl;
+    }
+
+    pub fn is_expired(&self) -> bool {
+        UTC::now().timestamp() >= self.expires
+    }
+}
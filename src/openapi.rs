@@ -0,0 +1,407 @@
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use config::Config;
+
+// Describes the API as an OpenAPI 3.0 document, for generating clients. It is
+// written out by hand, so wants updating along with the routes in main(), and
+// leaves out whatever the configuration turns off, e.g. renewing pastes when
+// max_renewals is 0. Errors are described once, as ERROR_RESPONSES says.
+pub fn spec(config: &Config, base_url: &str) -> Json {
+    let mut paths = BTreeMap::new();
+    let id = path_param("id", "The ID of a paste.");
+    let key = path_param("key", "The paste's edit key.");
+    // GET /{id}/<ext> and the edit key's methods share a path, so a parameter
+    let arg = path_param("arg", "For GET, the extension of a language; otherwise the paste's edit key.");
+
+    add(&mut paths, "/", "post", with_body(operation(
+        "Submits a paste. The options may also be given as form fields (with a multipart upload) or JSON \
+         fields (with a JSON body) rather than in the query string.",
+        submit_params(config),
+        submit_responses(config)), submission_body()));
+    add(&mut paths, "/batch", "post", with_body(operation(
+        &format!("Submits up to 100 pastes, each as for POST /, of up to {} bytes in total.", config.batch_max_bytes),
+        vec![],
+        vec![("200", json_response("The outcome for each paste, in order.", array(reference("BatchResult"))))]),
+        json_body(array(reference("JsonSubmission")))));
+    add(&mut paths, "/preview", "post", with_body(operation(
+        "Highlights the body as an HTML <pre> element without storing it.",
+        vec![query_param("lang", "string", "The extension of the language to highlight it as."),
+             query_param("theme", "string", "The highlighting theme, see /themes.")],
+        vec![("200", response("The highlighted paste.", vec![("text/html", schema("string"))]))]),
+        text_body()));
+
+    add(&mut paths, "/{id}", "get", operation(
+        "Retrieves a paste, highlighted for browsers, as it is for everybody else.",
+        vec![id.clone(),
+             query_param("password", "string", "The paste's password, if it has one; or in X-Paste-Password."),
+             query_param("highlight", "string", "\"auto\" to highlight it regardless, or \"false\" not to."),
+             query_param("theme", "string", "The highlighting theme, see /themes."),
+             query_param("nums", "boolean", "Whether to number the lines of the HTML view."),
+             query_param("wrap", "boolean", "Whether to wrap long lines in the HTML view."),
+             query_param("lines", "string", "A range of lines to highlight in the HTML view, e.g. \"10-20\"."),
+             query_param("color", "string", "For curl: \"auto\", \"always\" or \"never\".")],
+        vec![("200", response("The paste; or, to requests with \"Accept: application/json\", what there is \
+                               to know about it.",
+                               vec![("text/plain", schema("string")), ("text/html", schema("string")),
+                                    ("application/json", reference("PasteInfo"))])),
+             ("304", text_response("Unchanged since the ETag in If-None-Match.")),
+             ("401", text_response("The password is missing or wrong.")),
+             ("404", text_response("There is no such paste.")),
+             ("410", text_response("The paste has expired or been deleted."))]));
+    add(&mut paths, "/{id}/{arg}", "get", operation(
+        "Retrieves a paste highlighted as the language with the extension <arg>, see /languages. \
+         <arg>.png renders it as an image instead. Takes the query parameters of GET /{id}.",
+        vec![id.clone(), arg.clone()],
+        vec![("200", response("The highlighted paste.", vec![("text/plain", schema("string")),
+                                                             ("text/html", schema("string")),
+                                                             ("image/png", binary())])),
+             ("400", text_response("There is no such language."))]));
+    add(&mut paths, "/{id}/raw", "get", operation(
+        "Retrieves a paste exactly as it was submitted.",
+        vec![id.clone()],
+        vec![("200", response("The paste.", vec![("text/plain", schema("string")),
+                                                 ("application/octet-stream", binary())]))]));
+    add(&mut paths, "/{id}/download", "get", operation(
+        "Retrieves a paste as an attachment, named after its filename.",
+        vec![id.clone()],
+        vec![("200", response("The paste.", vec![("application/octet-stream", binary())]))]));
+    add(&mut paths, "/{id}/info", "get", operation(
+        "Describes a paste without retrieving it, as a page; GET /{id} gives the same as JSON.",
+        vec![id.clone()],
+        vec![("200", response("What there is to know about the paste.", vec![("text/html", schema("string"))]))]));
+    add(&mut paths, "/{id}/gist", "get", operation(
+        "Retrieves a paste in the form of a GitHub gist.",
+        vec![id.clone()],
+        vec![("200", json_response("The paste as a gist.", schema("object"))),
+             ("403", text_response("The paste is deleted after being viewed once."))]));
+    add(&mut paths, "/{id}/qr", "get", operation(
+        "A QR code of the paste's URL.",
+        vec![id.clone(), query_param("size", "integer", "The width and height of the image, in pixels.")],
+        vec![("200", response("The QR code.", vec![("image/png", binary())]))]));
+    if config.versions > 0 {
+        add(&mut paths, "/{id}/versions", "get", operation(
+            &format!("Lists the previous versions of a paste, oldest first; up to {} are kept.", config.versions),
+            vec![id.clone()],
+            vec![("200", json_response("The versions.", array(reference("Version"))))]));
+        add(&mut paths, "/{id}/versions/{version}", "get", operation(
+            "Retrieves a previous version of a paste, as /{id}/raw would.",
+            vec![id.clone(), path_param("version", "The number of the version.")],
+            vec![("200", text_response("The version.")),
+                 ("404", text_response("There is no such version."))]));
+        add(&mut paths, "/{id}/diff/{from}/{to}", "get", operation(
+            "A unified diff between two versions of a paste, either of which may be \"current\".",
+            vec![id.clone(), path_param("from", "The older version."), path_param("to", "The newer version.")],
+            vec![("200", response("The diff.", vec![("text/plain", schema("string")), ("text/html", schema("string"))]))]));
+    }
+
+    add(&mut paths, "/{id}/{arg}", "put", with_body(operation(
+        "Replaces a paste, or creates it anew if it has expired or been deleted (taking the query parameters \
+         of POST /). An If-Match header makes this conditional on the paste's ETag.",
+        vec![id.clone(), arg.clone()],
+        vec![("200", text_response("The paste was replaced.")),
+             ("201", text_response("The paste was created anew.")),
+             ("412", text_response("The paste has changed since the ETag in If-Match."))]), text_body()));
+    add(&mut paths, "/{id}/{arg}", "patch", with_body(operation(
+        "Appends the body to a paste.",
+        vec![id.clone(), arg.clone()],
+        vec![("200", text_response("The new size of the paste."))]), text_body()));
+    let deletion = if config.trash_days > 0 {
+        format!("Deletes a paste, which may be restored for {} days.", config.trash_days)
+    } else {
+        "Deletes a paste.".to_string()
+    };
+    add(&mut paths, "/{id}/{arg}", "delete", operation(
+        &deletion,
+        vec![id.clone(), arg.clone()],
+        vec![("200", text_response("The paste was deleted."))]));
+    if config.max_renewals > 0 {
+        add(&mut paths, "/{id}/{key}/renew", "post", operation(
+            &format!("Pushes back when a paste is deleted, up to {} times.", config.max_renewals),
+            vec![id.clone(), key.clone(), expires_param(config)],
+            vec![("200", text_response("When the paste will now be deleted.")),
+                 ("403", text_response("The paste may not be renewed again."))]));
+    }
+    if config.trash_days > 0 {
+        add(&mut paths, "/{id}/{key}/restore", "post", operation(
+            "Restores a deleted paste from the trash.",
+            vec![id.clone(), key.clone()],
+            vec![("200", text_response("The paste was restored.")),
+                 ("404", text_response("The paste is not in the trash.")),
+                 ("409", text_response("The paste's ID has been taken since."))]));
+    }
+
+    add(&mut paths, "/mine", "get", with_security(operation(
+        "Lists the pastes submitted with the API token.",
+        vec![],
+        vec![("200", json_response("The pastes.", array(reference("Listing"))))]), "token"));
+    add(&mut paths, "/tags/{tag}", "get", operation(
+        "Lists the pastes with a tag, 50 at a time; the Link header gives the next page.",
+        vec![path_param("tag", "The tag."), query_param("page", "integer", "Which page, from 1.")],
+        vec![("200", json_response("The pastes.", array(reference("Listing"))))]));
+    add(&mut paths, "/random", "get", operation(
+        "Redirects to a paste picked at random from those without a password or burn after reading.",
+        vec![],
+        vec![("302", text_response("The paste is in the Location header.")),
+             ("404", text_response("There are no such pastes."))]));
+    add(&mut paths, "/stats", "get", operation(
+        "Aggregate figures on the stored pastes.",
+        vec![],
+        vec![("200", json_response("The figures.", schema("object")))]));
+    add(&mut paths, "/themes", "get", operation(
+        "Lists the highlighting themes.",
+        vec![],
+        vec![("200", json_response("Their names.", array(schema("string"))))]));
+    add(&mut paths, "/languages", "get", operation(
+        "Lists the languages pastes may be highlighted as, with their extensions.",
+        vec![],
+        vec![("200", json_response("The languages.", array(reference("Language"))))]));
+    add(&mut paths, "/healthz", "get", operation(
+        "Whether the server can store and serve pastes.",
+        vec![],
+        vec![("200", text_response("It can.")),
+             ("503", text_response("It can't."))]));
+    if config.metrics {
+        add(&mut paths, "/metrics", "get", operation(
+            "Metrics in the Prometheus text format.",
+            vec![],
+            vec![("200", text_response("The metrics."))]));
+    }
+    if config.admin_password_hash.is_some() {
+        add(&mut paths, "/admin/pastes", "get", with_security(operation(
+            "Lists every paste, for the admin. The Link header gives the next page.",
+            vec![query_param("page", "integer", "Which page, from 1."),
+                 query_param("per_page", "integer", "How many pastes to a page.")],
+            vec![("200", json_response("The pastes.", array(reference("AdminListing"))))]), "admin"));
+        add(&mut paths, "/admin/{id}", "delete", with_security(operation(
+            "Deletes any paste without its key, for the admin.",
+            vec![id.clone()],
+            vec![("200", text_response("The paste was deleted."))]), "admin"));
+    }
+    add(&mut paths, "/openapi.json", "get", operation(
+        "This description of the API.",
+        vec![],
+        vec![("200", json_response("The description.", schema("object")))]));
+
+    let mut document = vec![
+        ("openapi", string("3.0.0")),
+        ("info", object(vec![
+            ("title", string("pastebin")),
+            ("version", string(env!("CARGO_PKG_VERSION"))),
+            ("description", string(&format!(
+                "Pastes may be up to {} bytes, and live for up to {} days. {}",
+                config.max_paste_bytes, config.paste_days, ERROR_RESPONSES)))
+        ])),
+        ("servers", Json::Array(vec![object(vec![("url", string(base_url))])])),
+        ("paths", Json::Object(paths.into_iter().map(|(path, operations)| (path, Json::Object(operations))).collect())),
+        ("components", object(vec![
+            ("schemas", schemas()),
+            ("securitySchemes", security_schemes(config))
+        ]))
+    ];
+    // everything but /healthz then needs the basic auth credentials
+    if config.auth_user.is_some() {
+        document.push(("security", Json::Array(vec![object(vec![("auth", Json::Array(vec![]))])])));
+    }
+    object(document)
+}
+
+const ERROR_RESPONSES: &'static str =
+    "Errors are given as text, or as JSON (see the Error schema) to requests with \
+     \"Accept: application/json\"; only those particular to each operation are listed.";
+
+fn submit_params(config: &Config) -> Vec<Json> {
+    vec![
+        expires_param(config),
+        query_param("burn", "boolean", "Whether to delete the paste once it has been viewed."),
+        query_param("password", "string", "A password required to view the paste."),
+        query_param("custom_id", "string", "An ID to use rather than a random one."),
+        query_param("namespace", "string", "A namespace to put the paste in."),
+        query_param("filename", "string", "The name of the pasted file; or in X-Filename."),
+        query_param("language", "string", "The extension of the language to highlight it as by default."),
+        query_param("title", "string", "A title for the paste."),
+        query_param("tags", "string", "Comma-separated tags, see /tags/{tag}.")
+    ]
+}
+
+fn submit_responses(config: &Config) -> Vec<(&'static str, Json)> {
+    let mut responses = vec![
+        ("201", response("The paste was created; its URL is also in the Location header.",
+                         vec![("text/plain", schema("string")), ("application/json", reference("Submission"))])),
+        ("409", text_response("The custom ID or namespace is taken.")),
+        ("413", text_response("The paste is too large.")),
+        ("415", text_response("The body is compressed in a way we don't support.")),
+        ("422", json_response("Fields of a JSON submission are invalid.", schema("object")))
+    ];
+    if config.max_pastes_per_ip > 0 {
+        responses.push(("403", text_response("This IP address has as many pastes as it may.")));
+    }
+    if config.rate_limit > 0 {
+        responses.push(("429", text_response("Too many pastes were submitted too quickly.")));
+    }
+    if config.disk_quota_bytes > 0 {
+        responses.push(("507", text_response("The server is full.")));
+    }
+    responses
+}
+
+fn expires_param(config: &Config) -> Json {
+    query_param("expires", "string", &format!(
+        "The lifetime of the paste, in seconds or with an s/m/h/d suffix; at most {} days.", config.paste_days))
+}
+
+fn schemas() -> Json {
+    object(vec![
+        ("Submission", properties(&[("id", "string"), ("key", "string"), ("view_url", "string"),
+                                    ("edit_url", "string"), ("delete_url", "string"), ("size", "integer")])),
+        ("JsonSubmission", properties(&[("content", "string"), ("expires", "string"), ("burn", "boolean"),
+                                        ("password", "string"), ("custom_id", "string"), ("namespace", "string"),
+                                        ("filename", "string"), ("language", "string"), ("title", "string"),
+                                        ("tags", "string")])),
+        ("BatchResult", properties(&[("id", "string"), ("key", "string"), ("url", "string"),
+                                     ("status", "integer"), ("error", "string"), ("errors", "object")])),
+        ("PasteInfo", properties(&[("id", "string"), ("size", "integer"), ("created", "string"),
+                                   ("expires", "string"), ("title", "string"), ("password_protected", "boolean"),
+                                   ("views", "integer"), ("language", "string"), ("tags", "array")])),
+        ("Version", properties(&[("version", "integer"), ("replaced", "string"), ("size", "integer")])),
+        ("Listing", properties(&[("id", "string"), ("title", "string"), ("size", "integer")])),
+        ("AdminListing", properties(&[("id", "string"), ("size", "integer"), ("modified", "string"),
+                                      ("age_secs", "integer"), ("submitter", "string")])),
+        ("Language", properties(&[("name", "string"), ("extensions", "array")])),
+        ("Error", object(vec![
+            ("type", string("object")),
+            ("properties", object(vec![
+                ("error", properties(&[("code", "string"), ("message", "string")]))
+            ]))
+        ]))
+    ])
+}
+
+fn security_schemes(config: &Config) -> Json {
+    let mut schemes = vec![
+        ("token", object(vec![("type", string("http")), ("scheme", string("bearer")),
+                              ("description", string("Any secret of at least 16 characters."))]))
+    ];
+    if config.auth_user.is_some() {
+        schemes.push(("auth", object(vec![("type", string("http")), ("scheme", string("basic"))])));
+    }
+    if config.admin_password_hash.is_some() {
+        schemes.push(("admin", object(vec![("type", string("http")), ("scheme", string("basic")),
+                                           ("description", string("As the user \"admin\"."))])));
+    }
+    object(schemes)
+}
+
+fn add(paths: &mut BTreeMap<String, BTreeMap<String, Json>>, path: &str, method: &str, operation: Json) {
+    paths.entry(path.to_string()).or_insert_with(BTreeMap::new).insert(method.to_string(), operation);
+}
+
+fn operation(summary: &str, parameters: Vec<Json>, responses: Vec<(&str, Json)>) -> Json {
+    object(vec![
+        ("summary", string(summary)),
+        ("parameters", Json::Array(parameters)),
+        ("responses", object(responses))
+    ])
+}
+
+fn with_body(mut operation: Json, body: Json) -> Json {
+    if let Json::Object(ref mut fields) = operation {
+        fields.insert("requestBody".to_string(), body);
+    }
+    operation
+}
+
+fn with_security(mut operation: Json, scheme: &str) -> Json {
+    if let Json::Object(ref mut fields) = operation {
+        fields.insert("security".to_string(), Json::Array(vec![object(vec![(scheme, Json::Array(vec![]))])]));
+    }
+    operation
+}
+
+fn submission_body() -> Json {
+    object(vec![("content", object(vec![
+        ("text/plain", object(vec![("schema", schema("string"))])),
+        ("application/octet-stream", object(vec![("schema", binary())])),
+        ("application/json", object(vec![("schema", reference("JsonSubmission"))])),
+        ("multipart/form-data", object(vec![("schema", properties(&[("file", "string")]))]))
+    ]))])
+}
+
+fn text_body() -> Json {
+    object(vec![("content", object(vec![
+        ("text/plain", object(vec![("schema", schema("string"))])),
+        ("application/octet-stream", object(vec![("schema", binary())]))
+    ]))])
+}
+
+fn json_body(schema: Json) -> Json {
+    object(vec![("content", object(vec![("application/json", object(vec![("schema", schema)]))]))])
+}
+
+fn path_param(name: &str, description: &str) -> Json {
+    param(name, "path", "string", description)
+}
+
+fn query_param(name: &str, kind: &str, description: &str) -> Json {
+    param(name, "query", kind, description)
+}
+
+// Path parameters are always required, and query parameters never are.
+fn param(name: &str, location: &str, kind: &str, description: &str) -> Json {
+    object(vec![
+        ("name", string(name)),
+        ("in", string(location)),
+        ("required", (location == "path").to_json()),
+        ("description", string(description)),
+        ("schema", schema(kind))
+    ])
+}
+
+fn response(description: &str, content: Vec<(&str, Json)>) -> Json {
+    object(vec![
+        ("description", string(description)),
+        ("content", object(content.into_iter().map(|(kind, schema)| (kind, object(vec![("schema", schema)]))).collect()))
+    ])
+}
+
+fn text_response(description: &str) -> Json {
+    response(description, vec![("text/plain", schema("string"))])
+}
+
+fn json_response(description: &str, schema: Json) -> Json {
+    response(description, vec![("application/json", schema)])
+}
+
+// Each field is of the given type, where arrays are all of strings.
+fn properties(fields: &[(&str, &str)]) -> Json {
+    let field = |kind: &str| if kind == "array" { array(schema("string")) } else { schema(kind) };
+    object(vec![
+        ("type", string("object")),
+        ("properties", object(fields.iter().map(|&(name, kind)| (name, field(kind))).collect()))
+    ])
+}
+
+fn schema(kind: &str) -> Json {
+    object(vec![("type", string(kind))])
+}
+
+fn binary() -> Json {
+    object(vec![("type", string("string")), ("format", string("binary"))])
+}
+
+fn array(items: Json) -> Json {
+    object(vec![("type", string("array")), ("items", items)])
+}
+
+fn reference(name: &str) -> Json {
+    object(vec![("$ref", string(&format!("#/components/schemas/{}", name)))])
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+}
+
+fn string(value: &str) -> Json {
+    Json::String(value.to_string())
+}
@@ -0,0 +1,74 @@
+use std::io;
+
+use image::ColorType;
+use image::png::PNGEncoder;
+use rusttype::{point, Font, Scale};
+use syntect::highlighting::{Color, Style};
+
+pub const MAX_LINES: usize = 500;
+const MAX_COLUMNS: usize = 200; // longer lines are cut off
+const FONT_SIZE: f32 = 16.0;
+const PADDING: u32 = 10;
+const TAB: &'static str = "    ";
+
+// Rasterizes highlighted lines (as produced by HighlightLines) onto the theme's
+// background, one character cell at a time, and encodes the result as a PNG.
+// The font is assumed to be monospaced.
+pub fn render(lines: &[Vec<(Style, String)>], background: Color, font: &Font) -> io::Result<Vec<u8>> {
+    let scale = Scale::uniform(FONT_SIZE);
+    let v_metrics = font.v_metrics(scale);
+    let line_height = (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap).ceil() as u32;
+    let advance = font.glyph('M').map_or(FONT_SIZE / 2.0, |glyph| glyph.scaled(scale).h_metrics().advance_width);
+
+    let columns = lines.iter()
+        .map(|ranges| ranges.iter().map(|&(_, ref text)| text.replace('\t', TAB).chars().count()).sum())
+        .max()
+        .unwrap_or(0);
+    let columns = ::std::cmp::min(columns, MAX_COLUMNS);
+    let width = (columns as f32 * advance).ceil() as u32 + 2 * PADDING;
+    let height = lines.len() as u32 * line_height + 2 * PADDING;
+
+    let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+    for _ in 0..width * height {
+        pixels.extend_from_slice(&[background.r, background.g, background.b]);
+    }
+
+    for (row, ranges) in lines.iter().enumerate() {
+        let baseline = PADDING as f32 + row as f32 * line_height as f32 + v_metrics.ascent;
+        let mut column = 0;
+        for &(ref style, ref text) in ranges {
+            for c in text.replace('\t', TAB).chars() {
+                if column >= MAX_COLUMNS {
+                    break;
+                }
+                let x = PADDING as f32 + column as f32 * advance;
+                column += 1;
+                let glyph = match font.glyph(c) {
+                    Some(glyph) => glyph.scaled(scale).positioned(point(x, baseline)),
+                    None => continue
+                };
+                let bounds = match glyph.pixel_bounding_box() {
+                    Some(bounds) => bounds,
+                    None => continue // e.g. spaces
+                };
+                let fg = style.foreground;
+                glyph.draw(|gx, gy, coverage| {
+                    let x = bounds.min.x + gx as i32;
+                    let y = bounds.min.y + gy as i32;
+                    if x < 0 || y < 0 || x as u32 >= width || y as u32 >= height {
+                        return;
+                    }
+                    let i = ((y as u32 * width + x as u32) * 3) as usize;
+                    for (channel, &value) in [fg.r, fg.g, fg.b].iter().enumerate() {
+                        let under = pixels[i + channel] as f32;
+                        pixels[i + channel] = (under + (value as f32 - under) * coverage).round() as u8;
+                    }
+                });
+            }
+        }
+    }
+
+    let mut png = Vec::new();
+    try!(PNGEncoder::new(&mut png).encode(&pixels, width, height, ColorType::RGB(8)));
+    Ok(png)
+}
@@ -0,0 +1,1521 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time;
+
+use chrono::UTC;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rand;
+use rusqlite::{self, Connection};
+
+use meta::PasteMeta;
+
+// Aggregate figures on the stored pastes, see Storage::stats().
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub pastes: u64,
+    pub bytes: u64,
+    // when the least and most recently modified pastes were, as unix timestamps
+    pub oldest: Option<i64>,
+    pub newest: Option<i64>
+}
+
+// Where pastes and their metadata live. Handlers only ever go through this, so
+// that the filesystem can be swapped out for something else.
+pub trait Storage: Send + Sync {
+    // Stores a new paste, failing with io::ErrorKind::AlreadyExists if the ID is taken.
+    fn create(&self, id: &str, paste: &[u8]) -> io::Result<()>;
+
+    // Stores a paste, replacing any existing paste with the same ID.
+    fn save(&self, id: &str, paste: &[u8]) -> io::Result<()>;
+
+    // Yields None if the paste does not exist.
+    fn load(&self, id: &str) -> io::Result<Option<Vec<u8>>>;
+
+    // Like load(), but for reading the paste a bit at a time rather than
+    // holding all of it in memory.
+    fn open(&self, id: &str) -> io::Result<Option<Box<Read + Send>>>;
+
+    // Loads a paste and deletes it as delete() does in one go, such that only
+    // one of several concurrent callers gets the paste. If it can't be read,
+    // it is left as it was.
+    fn take(&self, id: &str) -> io::Result<Option<Vec<u8>>>;
+
+    // Deletes a paste along with its metadata and previous versions.
+    fn delete(&self, id: &str) -> io::Result<()>;
+
+    fn exists(&self, id: &str) -> bool;
+
+    // Whether any paste has been stored in the namespace, i.e. with an ID of
+    // the form "<name>/<id>".
+    fn is_namespace(&self, name: &str) -> io::Result<bool>;
+
+    // The IDs of all pastes which expire at or before `cutoff` (a unix timestamp).
+    fn list_expired(&self, cutoff: i64) -> io::Result<Vec<String>>;
+
+    // Deletes all the pastes list_expired() would return, leaving tombstones
+    // in their place. Yields the ID of each along with either when it was last
+    // modified, or the error which kept it from being deleted, in which case
+    // it is left for next time rather than holding up the rest.
+    fn delete_expired(&self, cutoff: i64) -> io::Result<Vec<(String, io::Result<i64>)>> {
+        let mut results = Vec::new();
+        for id in try!(self.list_expired(cutoff)) {
+            let result = self.modified(&id).and_then(|modified| {
+                try!(self.delete(&id));
+                try!(self.bury(&id, "expired"));
+                Ok(modified)
+            });
+            results.push((id, result));
+        }
+        Ok(results)
+    }
+
+    // Records that a paste existed, and why it no longer does (e.g. "expired"),
+    // so that requests for it can be told as much.
+    fn bury(&self, id: &str, reason: &str) -> io::Result<()>;
+
+    // The reason a paste was buried with, if it was and is yet to be pruned.
+    fn tombstone(&self, id: &str) -> Option<String>;
+
+    // Forgets tombstones laid before `cutoff` (a unix timestamp), and says how many there were.
+    fn prune_tombstones(&self, cutoff: i64) -> io::Result<usize>;
+
+    // Moves a paste, along with its metadata and previous versions, into the
+    // trash rather than deleting it, so that restore() can bring it back until
+    // purge_trash() deletes it for good. Until then the paste doesn't exist as
+    // far as everything else is concerned.
+    fn trash(&self, id: &str) -> io::Result<()>;
+
+    // When a paste in the trash was put there (as a unix timestamp), along
+    // with its metadata if it had any. None if it isn't in the trash.
+    fn trashed(&self, id: &str) -> Option<(i64, Option<PasteMeta>)>;
+
+    // Takes a paste back out of the trash, failing with
+    // io::ErrorKind::AlreadyExists if its ID has been taken in the meantime.
+    // Yields false if it isn't in the trash.
+    fn restore(&self, id: &str) -> io::Result<bool>;
+
+    // Deletes the pastes put in the trash before `cutoff` (a unix timestamp)
+    // for good, and yields their IDs.
+    fn purge_trash(&self, cutoff: i64) -> io::Result<Vec<String>>;
+
+    fn load_meta(&self, id: &str) -> Option<PasteMeta>;
+
+    fn save_meta(&self, id: &str, meta: &PasteMeta) -> io::Result<()>;
+
+    // Keeps `paste` as the newest of a paste's previous versions (i.e. before
+    // it is replaced), forgetting all but the newest `keep` of them.
+    fn save_version(&self, id: &str, paste: &[u8], keep: usize) -> io::Result<()>;
+
+    // The numbers of a paste's previous versions, oldest first, along with
+    // when each was replaced (as a unix timestamp).
+    fn list_versions(&self, id: &str) -> io::Result<Vec<(u64, i64)>>;
+
+    // Yields None if there is no such version.
+    fn load_version(&self, id: &str, version: u64) -> io::Result<Option<Vec<u8>>>;
+
+    // The IDs of the pastes whose metadata records the given owner.
+    fn list_owned(&self, owner: &str) -> io::Result<Vec<String>>;
+
+    // The IDs of the pastes whose metadata records the given tag, in order.
+    fn list_tagged(&self, tag: &str) -> io::Result<Vec<String>>;
+
+    // How many pastes whose metadata records the given submitter haven't
+    // expired as of `now` (a unix timestamp).
+    fn count_submitted(&self, submitter: &str, now: i64) -> io::Result<u64>;
+
+    // The IDs of the pastes anybody may view as of `now` (a unix timestamp):
+    // those which haven't expired, and have neither a password nor are to be
+    // deleted once viewed.
+    fn list_viewable(&self, now: i64) -> io::Result<Vec<String>>;
+
+    // The ID of a paste whose metadata records the given content hash, if any.
+    fn find_by_hash(&self, hash: &str) -> io::Result<Option<String>>;
+
+    // When the paste was last written, as a unix timestamp.
+    fn modified(&self, id: &str) -> io::Result<i64>;
+
+    // The number of bytes the stored paste takes up, i.e. once compressed if
+    // the backend compresses it.
+    fn size(&self, id: &str) -> io::Result<u64>;
+
+    // The number of bytes everything stored takes up, measured as size() is:
+    // pastes, along with their previous versions and those in the trash.
+    fn usage(&self) -> io::Result<u64>;
+
+    // The number of stored pastes.
+    fn count(&self) -> io::Result<u64>;
+
+    // Up to `limit` of the IDs of all stored pastes, in order, skipping the
+    // first `offset` of them.
+    fn list_page(&self, offset: usize, limit: usize) -> io::Result<Vec<String>>;
+
+    // Looks at every paste, so may well be slow.
+    fn stats(&self) -> io::Result<Stats>;
+
+    // Whether pastes can currently be stored and retrieved. This is polled by
+    // load balancers, so should be cheap.
+    fn check(&self) -> io::Result<()>;
+}
+
+
+// Stores each paste gzipped in its own file, with its metadata as "key=value"
+// lines in a file of the same name in a separate directory.
+pub struct FilesystemStorage {
+    upload_dir: String,
+    metadata_dir: String,
+    default_ttl: i64 // for pastes from before metadata existed
+}
+
+impl FilesystemStorage {
+    pub fn new(upload_dir: &str, metadata_dir: &str, default_ttl: i64) -> io::Result<FilesystemStorage> {
+        try!(fs::create_dir_all(upload_dir));
+        try!(fs::create_dir_all(metadata_dir));
+        Ok(FilesystemStorage {
+            upload_dir: upload_dir.to_string(),
+            metadata_dir: metadata_dir.to_string(),
+            default_ttl: default_ttl
+        })
+    }
+
+    fn paste_path(&self, id: &str) -> String {
+        format!("{dir}/{id}", dir = self.upload_dir, id = id)
+    }
+
+    fn meta_path(&self, id: &str) -> String {
+        format!("{dir}/{id}", dir = self.metadata_dir, id = id)
+    }
+
+    // IDs can't contain dots either. Each previous version is a file named
+    // after its number in here, stored just as pastes are.
+    fn versions_dir(&self, id: &str) -> String {
+        format!("{dir}/{id}.versions", dir = self.metadata_dir, id = id)
+    }
+
+    fn tombstone_path(&self, id: &str) -> String {
+        format!("{dir}/{id}.gone", dir = self.metadata_dir, id = id)
+    }
+
+    // A trashed paste and its metadata keep their names with ".trash" added,
+    // and its previous versions likewise. When it was trashed is kept in a
+    // file of its own, which is also what marks it as being in the trash.
+    fn trash_path(path: &str) -> String {
+        format!("{}.trash", path)
+    }
+
+    fn trashed_path(&self, id: &str) -> String {
+        format!("{dir}/{id}.trashed", dir = self.metadata_dir, id = id)
+    }
+
+    // Everything in the metadata directory, including the namespaces in it,
+    // each along with the namespace it is in ("" or e.g. "team/"). Like
+    // list_ids(), skips whatever it can't make sense of.
+    fn metadata_files(&self) -> io::Result<Vec<(String, fs::DirEntry)>> {
+        let mut files: Vec<(String, fs::DirEntry)> = try!(fs::read_dir(&self.metadata_dir))
+            .filter_map(|file| file.ok())
+            .map(|file| (String::new(), file))
+            .collect();
+        let namespaces: Vec<_> = files.iter()
+            .map(|&(_, ref file)| file)
+            .filter(|file| !file.file_name().to_string_lossy().contains('.'))
+            .filter(|file| file.file_type().map(|kind| kind.is_dir()).unwrap_or(false))
+            .map(|file| (file.file_name().to_string_lossy().into_owned(), file.path()))
+            .collect();
+        for (namespace, path) in namespaces {
+            match fs::read_dir(&path) {
+                Ok(entries) => files.extend(entries.filter_map(|file| file.ok()).map(|file| (format!("{}/", namespace), file))),
+                Err(e) => println!("Skipping namespace {}: {}", path.display(), e)
+            }
+        }
+        Ok(files)
+    }
+
+    // The IDs of all stored pastes, including those in namespaces, which are
+    // subdirectories. Entries which can't be read are logged and skipped, so
+    // that one bad file can't hold up e.g. the cleanup of all the others.
+    fn list_ids(&self) -> io::Result<Vec<String>> {
+        let mut ids = Vec::new();
+        let mut namespaces = Vec::new();
+        for file in try!(fs::read_dir(&self.upload_dir)) {
+            let (name, kind) = match self.entry(file) {
+                Some(entry) => entry,
+                None => continue
+            };
+            if kind.is_dir() {
+                namespaces.push(name);
+            } else if kind.is_file() {
+                ids.push(name);
+            }
+        }
+        for namespace in namespaces {
+            let files = match fs::read_dir(self.paste_path(&namespace)) {
+                Ok(files) => files,
+                Err(e) => {
+                    println!("Skipping namespace {}: {}", namespace, e);
+                    continue;
+                }
+            };
+            for file in files {
+                match self.entry(file) {
+                    Some((name, ref kind)) if kind.is_file() => ids.push(format!("{}/{}", namespace, name)),
+                    _ => continue
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    // The name and type of a directory entry which could be a paste (or a
+    // namespace). IDs are never anything but ASCII, and never contain dots,
+    // unlike temporary files (see write_temp()) and pastes being taken.
+    fn entry(&self, file: io::Result<fs::DirEntry>) -> Option<(String, fs::FileType)> {
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                println!("Skipping unreadable entry in {}: {}", self.upload_dir, e);
+                return None;
+            }
+        };
+        let name = match file.file_name().into_string() {
+            Ok(ref name) if name.contains('.') => return None,
+            Ok(name) => name,
+            Err(_) => return None
+        };
+        file.file_type().ok().map(|kind| (name, kind))
+    }
+
+    // Maps a content hash to the ID of a paste with that content. IDs can't
+    // contain dots, so these can't be mistaken for any paste's metadata.
+    fn hash_path(&self, hash: &str) -> String {
+        format!("{dir}/{hash}.sha256", dir = self.metadata_dir, hash = hash)
+    }
+
+    // Lists the IDs of the pastes submitted from an address (see
+    // PasteMeta.submitter), one per line, so that they can be counted without
+    // reading every paste's metadata. Like the hash mappings, entries for
+    // pastes which have gone since are only tidied up when read.
+    fn submitted_path(&self, submitter: &str) -> String {
+        format!("{dir}/{submitter}.submitted", dir = self.metadata_dir, submitter = submitter)
+    }
+
+    fn read_submitted(&self, submitter: &str) -> io::Result<Vec<String>> {
+        let mut buffer = String::new();
+        match File::open(self.submitted_path(submitter)) {
+            Ok(mut f) => try!(f.read_to_string(&mut buffer)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e)
+        };
+        // concurrent submissions may each have listed the same paste
+        let mut ids: Vec<String> = Vec::new();
+        for id in buffer.lines().filter(|id| !id.is_empty()) {
+            if !ids.iter().any(|listed| listed == id) {
+                ids.push(id.to_string());
+            }
+        }
+        Ok(ids)
+    }
+
+    // Everything kept about a paste besides its contents, for delete() and take().
+    fn remove_rest(&self, id: &str) -> io::Result<()> {
+        if let Some(hash) = self.load_meta(id).and_then(|meta| meta.hash) {
+            try!(remove_if_exists(&self.hash_path(&hash)));
+        }
+        match fs::remove_dir_all(self.versions_dir(id)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            result => try!(result)
+        }
+        // pastes from before metadata existed have no metadata file
+        remove_if_exists(&self.meta_path(id))
+    }
+
+    fn read_meta(&self, path: &str) -> Option<PasteMeta> {
+        let mut f = match File::open(path) {
+            Ok(f) => f,
+            Err(_) => return None
+        };
+        let mut buffer = String::new();
+        if f.read_to_string(&mut buffer).is_err() {
+            return None;
+        }
+        let mut meta = PasteMeta::new(self.default_ttl);
+        for line in buffer.lines() {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("expires"), Some(value)) => meta.expires = value.parse().unwrap_or(meta.expires),
+                (Some("ttl"), Some(value)) => meta.ttl = value.parse().unwrap_or(meta.ttl),
+                (Some("burn"), Some(value)) => meta.burn = value == "true",
+                (Some("password"), Some(value)) => meta.password = Some(value.to_string()),
+                (Some("filename"), Some(value)) => meta.filename = Some(value.to_string()),
+                (Some("title"), Some(value)) => meta.title = Some(value.to_string()),
+                (Some("language"), Some(value)) => meta.language = Some(value.to_string()),
+                (Some("views"), Some(value)) => meta.views = value.parse().unwrap_or(0),
+                (Some("hash"), Some(value)) => meta.hash = Some(value.to_string()),
+                (Some("refs"), Some(value)) => meta.refs = value.parse().unwrap_or(1),
+                (Some("key_hash"), Some(value)) => meta.key_hash = Some(value.to_string()),
+                (Some("owner"), Some(value)) => meta.owner = Some(value.to_string()),
+                (Some("submitter"), Some(value)) => meta.submitter = Some(value.to_string()),
+                (Some("tags"), Some(value)) => meta.tags = split_list(value),
+                (Some("ref_keys"), Some(value)) => meta.ref_keys = split_list(value),
+                (Some("renewals"), Some(value)) => meta.renewals = value.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+        Some(meta)
+    }
+}
+
+impl Storage for FilesystemStorage {
+    fn create(&self, id: &str, paste: &[u8]) -> io::Result<()> {
+        // unlike renaming, linking fails if the ID is already taken, so that two
+        // requests for the same ID can't both succeed
+        if let Some(i) = id.find('/') {
+            try!(fs::create_dir_all(self.paste_path(&id[..i])));
+            try!(fs::create_dir_all(self.meta_path(&id[..i])));
+        }
+        let path = self.paste_path(id);
+        let temp = try!(write_temp(&path, |f| write_paste(f, paste)));
+        let linked = fs::hard_link(&temp, &path);
+        try!(fs::remove_file(&temp));
+        linked
+    }
+
+    fn save(&self, id: &str, paste: &[u8]) -> io::Result<()> {
+        let path = self.paste_path(id);
+        let temp = try!(write_temp(&path, |f| write_paste(f, paste)));
+        rename_or_remove(&temp, &path)
+    }
+
+    fn load(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut f = match File::open(self.paste_path(id)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        read_paste(&mut f).map(Some)
+    }
+
+    fn open(&self, id: &str) -> io::Result<Option<Box<Read + Send>>> {
+        let mut f = match File::open(self.paste_path(id)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        // see read_paste()
+        let mut magic = [0; 2];
+        let gzipped = try!(f.read(&mut magic)) == 2 && magic == [0x1f, 0x8b];
+        try!(f.seek(SeekFrom::Start(0)));
+        if gzipped {
+            Ok(Some(Box::new(try!(GzDecoder::new(f)))))
+        } else {
+            Ok(Some(Box::new(f)))
+        }
+    }
+
+    fn take(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        // Whoever manages to move the paste out of the way gets to read it.
+        // Anybody racing them for it will find that it no longer exists.
+        let path = self.paste_path(id);
+        let claimed = format!("{path}.{tag:x}", path = path, tag = rand::random::<u64>());
+        if fs::rename(&path, &claimed).is_err() {
+            return Ok(None);
+        }
+        let paste = match File::open(&claimed).and_then(|mut f| read_paste(&mut f)) {
+            Ok(paste) => paste,
+            Err(e) => {
+                let _ = fs::rename(&claimed, &path);
+                return Err(e);
+            }
+        };
+        try!(fs::remove_file(&claimed));
+        try!(self.remove_rest(id));
+        Ok(Some(paste))
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        try!(fs::remove_file(self.paste_path(id)));
+        self.remove_rest(id)
+    }
+
+    fn exists(&self, id: &str) -> bool {
+        Path::new(&self.paste_path(id)).is_file()
+    }
+
+    // each namespace is a subdirectory of both directories
+    fn is_namespace(&self, name: &str) -> io::Result<bool> {
+        Ok(Path::new(&self.paste_path(name)).is_dir())
+    }
+
+    fn list_expired(&self, cutoff: i64) -> io::Result<Vec<String>> {
+        let mut expired = Vec::new();
+        for id in try!(self.list_ids()) {
+            let expires = match self.load_meta(&id) {
+                Some(meta) => meta.expires,
+                // pastes from before metadata existed: fall back to age
+                None => match self.modified(&id) {
+                    Ok(modified) => modified + self.default_ttl,
+                    Err(e) => {
+                        println!("Skipping paste {}: {}", id, e);
+                        continue;
+                    }
+                }
+            };
+            if expires <= cutoff {
+                expired.push(id);
+            }
+        }
+        Ok(expired)
+    }
+
+    fn load_meta(&self, id: &str) -> Option<PasteMeta> {
+        self.read_meta(&self.meta_path(id))
+    }
+
+    fn save_meta(&self, id: &str, meta: &PasteMeta) -> io::Result<()> {
+        let path = self.meta_path(id);
+        let temp = try!(write_temp(&path, |f| {
+            try!(write!(f, "expires={}\nttl={}\nburn={}\nviews={}\nrefs={}\nrenewals={}\n",
+                        meta.expires, meta.ttl, meta.burn, meta.views, meta.refs, meta.renewals));
+            if let Some(ref password) = meta.password {
+                try!(write!(f, "password={}\n", password));
+            }
+            if let Some(ref filename) = meta.filename {
+                try!(write!(f, "filename={}\n", filename));
+            }
+            if let Some(ref title) = meta.title {
+                try!(write!(f, "title={}\n", title));
+            }
+            if let Some(ref language) = meta.language {
+                try!(write!(f, "language={}\n", language));
+            }
+            if let Some(ref hash) = meta.hash {
+                try!(write!(f, "hash={}\n", hash));
+            }
+            if let Some(ref key_hash) = meta.key_hash {
+                try!(write!(f, "key_hash={}\n", key_hash));
+            }
+            if let Some(ref owner) = meta.owner {
+                try!(write!(f, "owner={}\n", owner));
+            }
+            if let Some(ref submitter) = meta.submitter {
+                try!(write!(f, "submitter={}\n", submitter));
+            }
+            if !meta.tags.is_empty() {
+                try!(write!(f, "tags={}\n", meta.tags.join(",")));
+            }
+            if !meta.ref_keys.is_empty() {
+                try!(write!(f, "ref_keys={}\n", meta.ref_keys.join(",")));
+            }
+            Ok(())
+        }));
+        try!(rename_or_remove(&temp, &path));
+        if let Some(ref hash) = meta.hash {
+            let path = self.hash_path(hash);
+            let temp = try!(write_temp(&path, |f| f.write_all(id.as_bytes())));
+            try!(rename_or_remove(&temp, &path));
+        }
+        // appended to, so that concurrent submissions don't lose each other's
+        if let Some(ref submitter) = meta.submitter {
+            if !try!(self.read_submitted(submitter)).iter().any(|listed| listed == id) {
+                let mut f = try!(OpenOptions::new().create(true).append(true).open(self.submitted_path(submitter)));
+                try!(write!(f, "{}\n", id));
+            }
+        }
+        Ok(())
+    }
+
+    fn save_version(&self, id: &str, paste: &[u8], keep: usize) -> io::Result<()> {
+        let dir = self.versions_dir(id);
+        try!(fs::create_dir_all(&dir));
+        let versions = try!(self.list_versions(id));
+        let next = versions.last().map_or(1, |&(version, _)| version + 1);
+        let path = format!("{dir}/{version}", dir = dir, version = next);
+        let temp = try!(write_temp(&path, |f| write_paste(f, paste)));
+        try!(rename_or_remove(&temp, &path));
+        // counting the one just saved
+        let excess = (versions.len() + 1).saturating_sub(keep);
+        for &(version, _) in versions.iter().take(excess) {
+            try!(remove_if_exists(&format!("{dir}/{version}", dir = dir, version = version)));
+        }
+        Ok(())
+    }
+
+    // the time each version was replaced is its file's mtime
+    fn list_versions(&self, id: &str) -> io::Result<Vec<(u64, i64)>> {
+        let entries = match fs::read_dir(self.versions_dir(id)) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e)
+        };
+        let mut versions = Vec::new();
+        for file in entries {
+            let file = try!(file);
+            // skips any temporary files left over from write_temp()
+            let version = match file.file_name().to_string_lossy().parse::<u64>() {
+                Ok(version) => version,
+                Err(_) => continue
+            };
+            let saved = try!(try!(file.metadata()).modified());
+            versions.push((version, saved.duration_since(time::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)));
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    fn load_version(&self, id: &str, version: u64) -> io::Result<Option<Vec<u8>>> {
+        let path = format!("{dir}/{version}", dir = self.versions_dir(id), version = version);
+        let mut f = match File::open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        read_paste(&mut f).map(Some)
+    }
+
+    // This reads every paste's metadata, so is only really suitable for small
+    // servers; the sqlite backend keeps an index instead.
+    fn list_owned(&self, owner: &str) -> io::Result<Vec<String>> {
+        let mut owned = Vec::new();
+        for id in try!(self.list_ids()) {
+            if self.load_meta(&id).and_then(|meta| meta.owner).as_ref().map(|o| o.as_str()) == Some(owner) {
+                owned.push(id);
+            }
+        }
+        owned.sort();
+        Ok(owned)
+    }
+
+    // Likewise.
+    fn list_tagged(&self, tag: &str) -> io::Result<Vec<String>> {
+        let mut tagged = Vec::new();
+        for id in try!(self.list_ids()) {
+            if self.load_meta(&id).map_or(false, |meta| meta.tags.iter().any(|t| t == tag)) {
+                tagged.push(id);
+            }
+        }
+        tagged.sort();
+        Ok(tagged)
+    }
+
+    // Only reads the metadata of the pastes listed for the submitter, see
+    // submitted_path(), and forgets those which have gone or expired.
+    fn count_submitted(&self, submitter: &str, now: i64) -> io::Result<u64> {
+        let listed = try!(self.read_submitted(submitter));
+        let live: Vec<&String> = listed.iter()
+            .filter(|id| match self.load_meta(id) {
+                Some(meta) => meta.submitter.as_ref().map(|s| s.as_str()) == Some(submitter) && meta.expires > now,
+                None => false
+            })
+            .collect();
+        if live.len() < listed.len() {
+            let path = self.submitted_path(submitter);
+            if live.is_empty() {
+                try!(remove_if_exists(&path));
+            } else {
+                let temp = try!(write_temp(&path, |f| {
+                    for id in &live {
+                        try!(write!(f, "{}\n", id));
+                    }
+                    Ok(())
+                }));
+                try!(rename_or_remove(&temp, &path));
+            }
+        }
+        Ok(live.len() as u64)
+    }
+
+    // Pastes from before metadata existed are left to list_expired() to judge.
+    fn list_viewable(&self, now: i64) -> io::Result<Vec<String>> {
+        let mut viewable = Vec::new();
+        for id in try!(self.list_ids()) {
+            match self.load_meta(&id) {
+                Some(ref meta) if meta.expires <= now || meta.burn || meta.password.is_some() => {},
+                _ => viewable.push(id)
+            }
+        }
+        Ok(viewable)
+    }
+
+    fn find_by_hash(&self, hash: &str) -> io::Result<Option<String>> {
+        let mut id = String::new();
+        match File::open(self.hash_path(hash)) {
+            Ok(mut f) => try!(f.read_to_string(&mut id)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        // The paste may have been changed or taken (e.g. burnt) since, which
+        // leaves the mapping behind. It is only worth tidying up when found.
+        if self.load_meta(&id).and_then(|meta| meta.hash).as_ref().map(|h| h.as_str()) != Some(hash) {
+            try!(remove_if_exists(&self.hash_path(hash)));
+            return Ok(None);
+        }
+        Ok(Some(id))
+    }
+
+    // the reason is the file's content, and the time it was buried its mtime
+    fn bury(&self, id: &str, reason: &str) -> io::Result<()> {
+        let path = self.tombstone_path(id);
+        let temp = try!(write_temp(&path, |f| f.write_all(reason.as_bytes())));
+        rename_or_remove(&temp, &path)
+    }
+
+    fn tombstone(&self, id: &str) -> Option<String> {
+        let mut reason = String::new();
+        match File::open(self.tombstone_path(id)).and_then(|mut f| f.read_to_string(&mut reason)) {
+            Ok(_) => Some(reason),
+            Err(_) => None
+        }
+    }
+
+    fn prune_tombstones(&self, cutoff: i64) -> io::Result<usize> {
+        let mut pruned = 0;
+        for (_, file) in try!(self.metadata_files()) {
+            if !file.file_name().to_string_lossy().ends_with(".gone") {
+                continue;
+            }
+            let buried = match file.metadata().and_then(|attr| attr.modified()) {
+                Ok(buried) => buried.duration_since(time::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0),
+                Err(e) => {
+                    println!("Skipping tombstone {}: {}", file.path().display(), e);
+                    continue;
+                }
+            };
+            if buried <= cutoff {
+                try!(remove_if_exists(&file.path().to_string_lossy()));
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    fn trash(&self, id: &str) -> io::Result<()> {
+        // the paste's content is nobody else's to share any more
+        if let Some(hash) = self.load_meta(id).and_then(|meta| meta.hash) {
+            try!(remove_if_exists(&self.hash_path(&hash)));
+        }
+        try!(fs::rename(self.paste_path(id), FilesystemStorage::trash_path(&self.paste_path(id))));
+        for path in &[self.meta_path(id), self.versions_dir(id)] {
+            match fs::rename(path, FilesystemStorage::trash_path(path)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                result => try!(result)
+            }
+        }
+        let now = UTC::now().timestamp();
+        let path = self.trashed_path(id);
+        let temp = try!(write_temp(&path, |f| write!(f, "{}", now)));
+        rename_or_remove(&temp, &path)
+    }
+
+    fn trashed(&self, id: &str) -> Option<(i64, Option<PasteMeta>)> {
+        let mut trashed = String::new();
+        if File::open(self.trashed_path(id)).and_then(|mut f| f.read_to_string(&mut trashed)).is_err() {
+            return None;
+        }
+        let meta = self.read_meta(&FilesystemStorage::trash_path(&self.meta_path(id)));
+        trashed.trim().parse().ok().map(|trashed| (trashed, meta))
+    }
+
+    fn restore(&self, id: &str) -> io::Result<bool> {
+        if !Path::new(&self.trashed_path(id)).is_file() {
+            return Ok(false);
+        }
+        // as in create(), linking fails rather than replacing a paste which has
+        // taken the ID in the meantime
+        let path = self.paste_path(id);
+        try!(fs::hard_link(FilesystemStorage::trash_path(&path), &path));
+        try!(fs::remove_file(FilesystemStorage::trash_path(&path)));
+        for path in &[self.meta_path(id), self.versions_dir(id)] {
+            match fs::rename(FilesystemStorage::trash_path(path), path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                result => try!(result)
+            }
+        }
+        try!(remove_if_exists(&self.trashed_path(id)));
+        // and for the sake of its content hash, see trash()
+        if let Some(meta) = self.load_meta(id) {
+            try!(self.save_meta(id, &meta));
+        }
+        Ok(true)
+    }
+
+    fn purge_trash(&self, cutoff: i64) -> io::Result<Vec<String>> {
+        let mut purged = Vec::new();
+        for (namespace, file) in try!(self.metadata_files()) {
+            let name = file.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".trashed") {
+                continue;
+            }
+            let id = format!("{}{}", namespace, &name[..name.len() - ".trashed".len()]);
+            let trashed = match self.trashed(&id) {
+                Some((trashed, _)) => trashed,
+                None => {
+                    println!("Skipping trashed paste {}: unreadable", id);
+                    continue;
+                }
+            };
+            if trashed > cutoff {
+                continue;
+            }
+            try!(remove_if_exists(&FilesystemStorage::trash_path(&self.paste_path(&id))));
+            try!(remove_if_exists(&FilesystemStorage::trash_path(&self.meta_path(&id))));
+            match fs::remove_dir_all(FilesystemStorage::trash_path(&self.versions_dir(&id))) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                result => try!(result)
+            }
+            try!(remove_if_exists(&self.trashed_path(&id)));
+            purged.push(id);
+        }
+        Ok(purged)
+    }
+
+    // Measured from the epoch rather than from now, so that an mtime in the
+    // future (e.g. after the clock has been set back) only makes the paste seem
+    // newer than it is, rather than failing.
+    fn modified(&self, id: &str) -> io::Result<i64> {
+        let modified = try!(try!(fs::metadata(self.paste_path(id))).modified());
+        Ok(modified.duration_since(time::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0))
+    }
+
+    fn size(&self, id: &str) -> io::Result<u64> {
+        fs::metadata(self.paste_path(id)).map(|attr| attr.len())
+    }
+
+    // Everything in both directories, metadata included.
+    fn usage(&self) -> io::Result<u64> {
+        Ok(try!(dir_size(Path::new(&self.upload_dir))) + try!(dir_size(Path::new(&self.metadata_dir))))
+    }
+
+    fn count(&self) -> io::Result<u64> {
+        Ok(try!(self.list_ids()).len() as u64)
+    }
+
+    // Only the directory listing is read, not any of the pastes in it.
+    fn list_page(&self, offset: usize, limit: usize) -> io::Result<Vec<String>> {
+        let mut ids = try!(self.list_ids());
+        ids.sort();
+        Ok(ids.into_iter().skip(offset).take(limit).collect())
+    }
+
+    // pastes which disappear part way through are left out
+    fn stats(&self) -> io::Result<Stats> {
+        let mut stats = Stats { pastes: 0, bytes: 0, oldest: None, newest: None };
+        for id in try!(self.list_ids()) {
+            let (size, modified) = match (self.size(&id), self.modified(&id)) {
+                (Ok(size), Ok(modified)) => (size, modified),
+                _ => continue
+            };
+            stats.pastes += 1;
+            stats.bytes += size;
+            stats.oldest = Some(stats.oldest.map_or(modified, |oldest| ::std::cmp::min(oldest, modified)));
+            stats.newest = Some(stats.newest.map_or(modified, |newest| ::std::cmp::max(newest, modified)));
+        }
+        Ok(stats)
+    }
+
+    fn check(&self) -> io::Result<()> {
+        for dir in &[&self.upload_dir, &self.metadata_dir] {
+            let attr = try!(fs::metadata(dir));
+            if !attr.is_dir() || attr.permissions().readonly() {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is not a writable directory", dir)));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// Every column of the pastes table, and those holding metadata in the order row_meta() expects.
+const PASTE_COLUMNS: &'static str = "id, content, created, modified, expires, ttl, burn, password, filename, views, title, hash, refs, key_hash, owner, renewals, language, submitter, tags, ref_keys";
+const META_COLUMNS: &'static str = "expires, ttl, burn, password, filename, views, title, hash, refs, key_hash, owner, renewals, language, submitter, tags, ref_keys";
+
+// Stores everything in a single table, which copes far better than a directory
+// of files once there are hundreds of thousands of pastes. The metadata columns
+// stay NULL until save_meta() is first called for a paste.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    default_ttl: i64 // for pastes which have no metadata
+}
+
+impl SqliteStorage {
+    pub fn new(path: &str, default_ttl: i64) -> io::Result<SqliteStorage> {
+        let conn = try!(Connection::open(path).map_err(sql_error));
+        try!(conn.execute("CREATE TABLE IF NOT EXISTS pastes (
+                               id       TEXT PRIMARY KEY,
+                               content  BLOB NOT NULL,
+                               created  INTEGER NOT NULL,
+                               modified INTEGER NOT NULL,
+                               expires  INTEGER,
+                               ttl      INTEGER,
+                               burn     INTEGER,
+                               password TEXT,
+                               filename TEXT,
+                               views    INTEGER,
+                               title    TEXT,
+                               hash     TEXT,
+                               refs     INTEGER,
+                               key_hash TEXT,
+                               owner    TEXT,
+                               renewals INTEGER,
+                               language TEXT,
+                               submitter TEXT,
+                               tags     TEXT,
+                               ref_keys TEXT
+                           )", &[]).map_err(sql_error));
+        // for databases created before the columns were
+        try!(add_column_if_missing(&conn, "pastes", "title", "TEXT"));
+        try!(add_column_if_missing(&conn, "pastes", "hash", "TEXT"));
+        try!(add_column_if_missing(&conn, "pastes", "refs", "INTEGER"));
+        try!(add_column_if_missing(&conn, "pastes", "key_hash", "TEXT"));
+        try!(add_column_if_missing(&conn, "pastes", "owner", "TEXT"));
+        try!(add_column_if_missing(&conn, "pastes", "renewals", "INTEGER"));
+        try!(add_column_if_missing(&conn, "pastes", "language", "TEXT"));
+        try!(add_column_if_missing(&conn, "pastes", "submitter", "TEXT"));
+        try!(add_column_if_missing(&conn, "pastes", "tags", "TEXT"));
+        try!(add_column_if_missing(&conn, "pastes", "ref_keys", "TEXT"));
+        try!(conn.execute("CREATE INDEX IF NOT EXISTS pastes_expires ON pastes (expires)", &[]).map_err(sql_error));
+        try!(conn.execute("CREATE INDEX IF NOT EXISTS pastes_hash ON pastes (hash)", &[]).map_err(sql_error));
+        try!(conn.execute("CREATE INDEX IF NOT EXISTS pastes_owner ON pastes (owner)", &[]).map_err(sql_error));
+        try!(conn.execute("CREATE INDEX IF NOT EXISTS pastes_submitter ON pastes (submitter)", &[]).map_err(sql_error));
+        try!(conn.execute("CREATE TABLE IF NOT EXISTS tombstones (
+                               id     TEXT PRIMARY KEY,
+                               reason TEXT NOT NULL,
+                               buried INTEGER NOT NULL
+                           )", &[]).map_err(sql_error));
+        // trashed pastes, with the columns of the pastes table (see PASTE_COLUMNS)
+        try!(conn.execute("CREATE TABLE IF NOT EXISTS trash (
+                               id       TEXT PRIMARY KEY,
+                               trashed  INTEGER NOT NULL,
+                               content  BLOB NOT NULL,
+                               created  INTEGER NOT NULL,
+                               modified INTEGER NOT NULL,
+                               expires  INTEGER,
+                               ttl      INTEGER,
+                               burn     INTEGER,
+                               password TEXT,
+                               filename TEXT,
+                               views    INTEGER,
+                               title    TEXT,
+                               hash     TEXT,
+                               refs     INTEGER,
+                               key_hash TEXT,
+                               owner    TEXT,
+                               renewals INTEGER,
+                               language TEXT,
+                               submitter TEXT,
+                               tags     TEXT,
+                               ref_keys TEXT
+                           )", &[]).map_err(sql_error));
+        try!(add_column_if_missing(&conn, "trash", "submitter", "TEXT"));
+        try!(add_column_if_missing(&conn, "trash", "tags", "TEXT"));
+        try!(add_column_if_missing(&conn, "trash", "ref_keys", "TEXT"));
+        try!(conn.execute("CREATE TABLE IF NOT EXISTS versions (
+                               id      TEXT NOT NULL,
+                               version INTEGER NOT NULL,
+                               content BLOB NOT NULL,
+                               saved   INTEGER NOT NULL,
+                               PRIMARY KEY (id, version)
+                           )", &[]).map_err(sql_error));
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+            default_ttl: default_ttl
+        })
+    }
+}
+
+impl SqliteStorage {
+    // The metadata in the META_COLUMNS of a row, starting at column `first`.
+    fn row_meta(&self, row: &rusqlite::Row, first: i32) -> PasteMeta {
+        PasteMeta {
+            expires: row.get(first),
+            ttl: row.get::<i32, Option<i64>>(first + 1).unwrap_or(self.default_ttl),
+            burn: row.get::<i32, Option<bool>>(first + 2).unwrap_or(false),
+            password: row.get(first + 3),
+            filename: row.get(first + 4),
+            views: row.get::<i32, Option<i64>>(first + 5).unwrap_or(0) as u64,
+            title: row.get(first + 6),
+            hash: row.get(first + 7),
+            refs: row.get::<i32, Option<i64>>(first + 8).unwrap_or(1) as u64,
+            key_hash: row.get(first + 9),
+            owner: row.get(first + 10),
+            renewals: row.get::<i32, Option<i64>>(first + 11).unwrap_or(0) as u32,
+            language: row.get(first + 12),
+            submitter: row.get(first + 13),
+            tags: row.get::<i32, Option<String>>(first + 14).map_or_else(Vec::new, |tags| split_list(&tags)),
+            ref_keys: row.get::<i32, Option<String>>(first + 15).map_or_else(Vec::new, |keys| split_list(&keys))
+        }
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn create(&self, id: &str, paste: &[u8]) -> io::Result<()> {
+        // holding the connection makes checking and inserting a single step
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = try!(conn.query_row("SELECT COUNT(*) FROM pastes WHERE id = ?",
+                                             &[&id], |row| row.get(0)).map_err(sql_error));
+        if count > 0 {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("paste {} already exists", id)));
+        }
+        let now = UTC::now().timestamp();
+        try!(conn.execute("INSERT INTO pastes (id, content, created, modified) VALUES (?, ?, ?, ?)",
+                          &[&id, &paste, &now, &now]).map_err(sql_error));
+        Ok(())
+    }
+
+    fn save(&self, id: &str, paste: &[u8]) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let now = UTC::now().timestamp();
+        let updated = try!(conn.execute("UPDATE pastes SET content = ?, modified = ? WHERE id = ?",
+                                        &[&paste, &now, &id]).map_err(sql_error));
+        if updated == 0 {
+            try!(conn.execute("INSERT INTO pastes (id, content, created, modified) VALUES (?, ?, ?, ?)",
+                              &[&id, &paste, &now, &now]).map_err(sql_error));
+        }
+        Ok(())
+    }
+
+    fn load(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock().unwrap();
+        optional(conn.query_row("SELECT content FROM pastes WHERE id = ?", &[&id], |row| row.get(0)))
+    }
+
+    // Pastes are small enough for SQLite to hand back whole anyway.
+    fn open(&self, id: &str) -> io::Result<Option<Box<Read + Send>>> {
+        let paste = try!(self.load(id));
+        Ok(paste.map(|paste| Box::new(io::Cursor::new(paste)) as Box<Read + Send>))
+    }
+
+    // Its previous versions go along with it, as with delete().
+    fn take(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction().map_err(sql_error));
+        let paste = try!(optional(tx.query_row("SELECT content FROM pastes WHERE id = ?",
+                                               &[&id], |row| row.get(0))));
+        if paste.is_some() {
+            try!(tx.execute("DELETE FROM pastes WHERE id = ?", &[&id]).map_err(sql_error));
+            try!(tx.execute("DELETE FROM versions WHERE id = ?", &[&id]).map_err(sql_error));
+        }
+        try!(tx.commit().map_err(sql_error));
+        Ok(paste)
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let deleted = try!(conn.execute("DELETE FROM pastes WHERE id = ?", &[&id]).map_err(sql_error));
+        if deleted == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("paste {} does not exist", id)));
+        }
+        try!(conn.execute("DELETE FROM versions WHERE id = ?", &[&id]).map_err(sql_error));
+        Ok(())
+    }
+
+    fn exists(&self, id: &str) -> bool {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT COUNT(*) FROM pastes WHERE id = ?", &[&id], |row| row.get::<i32, i64>(0))
+            .map_or(false, |count| count > 0)
+    }
+
+    // written as a range rather than with LIKE, so that the primary key's index
+    // is used; '0' is the character after '/'
+    fn is_namespace(&self, name: &str) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = try!(conn.query_row("SELECT COUNT(*) FROM pastes WHERE id > ? AND id < ?",
+                                             &[&format!("{}/", name), &format!("{}0", name)],
+                                             |row| row.get(0)).map_err(sql_error));
+        Ok(count > 0)
+    }
+
+    fn list_expired(&self, cutoff: i64) -> io::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT id FROM pastes WHERE COALESCE(expires, modified + ?) <= ?")
+                                .map_err(sql_error));
+        let rows = try!(stmt.query_map(&[&self.default_ttl, &cutoff], |row| row.get(0)).map_err(sql_error));
+        let mut expired = Vec::new();
+        for id in rows {
+            expired.push(try!(id.map_err(sql_error)));
+        }
+        Ok(expired)
+    }
+
+    // a few queries, rather than round trips per paste
+    fn delete_expired(&self, cutoff: i64) -> io::Result<Vec<(String, io::Result<i64>)>> {
+        let conn = self.conn.lock().unwrap();
+        let now = UTC::now().timestamp();
+        let mut results = Vec::new();
+        {
+            let mut stmt = try!(conn.prepare("SELECT id, modified FROM pastes WHERE COALESCE(expires, modified + ?) <= ?")
+                                    .map_err(sql_error));
+            let rows = try!(stmt.query_map(&[&self.default_ttl, &cutoff], |row| (row.get(0), row.get(1)))
+                                .map_err(sql_error));
+            for row in rows {
+                let (id, modified): (String, i64) = try!(row.map_err(sql_error));
+                results.push((id, Ok(modified)));
+            }
+        }
+        try!(conn.execute("INSERT OR REPLACE INTO tombstones (id, reason, buried)
+                           SELECT id, 'expired', ? FROM pastes WHERE COALESCE(expires, modified + ?) <= ?",
+                          &[&now, &self.default_ttl, &cutoff]).map_err(sql_error));
+        try!(conn.execute("DELETE FROM versions WHERE id IN
+                               (SELECT id FROM pastes WHERE COALESCE(expires, modified + ?) <= ?)",
+                          &[&self.default_ttl, &cutoff]).map_err(sql_error));
+        try!(conn.execute("DELETE FROM pastes WHERE COALESCE(expires, modified + ?) <= ?",
+                          &[&self.default_ttl, &cutoff]).map_err(sql_error));
+        Ok(results)
+    }
+
+    fn bury(&self, id: &str, reason: &str) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let now = UTC::now().timestamp();
+        try!(conn.execute("INSERT OR REPLACE INTO tombstones (id, reason, buried) VALUES (?, ?, ?)",
+                          &[&id, &reason, &now]).map_err(sql_error));
+        Ok(())
+    }
+
+    fn tombstone(&self, id: &str) -> Option<String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT reason FROM tombstones WHERE id = ?", &[&id], |row| row.get(0)).ok()
+    }
+
+    fn prune_tombstones(&self, cutoff: i64) -> io::Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let pruned = try!(conn.execute("DELETE FROM tombstones WHERE buried <= ?", &[&cutoff]).map_err(sql_error));
+        Ok(pruned as usize)
+    }
+
+    // Trashed pastes keep their rows in the trash table, and their previous
+    // versions under their ID with ".trash" added, which no paste can have.
+    fn trash(&self, id: &str) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let now = UTC::now().timestamp();
+        let trash_id = format!("{}.trash", id);
+        let trashed = try!(conn.execute(&format!("INSERT OR REPLACE INTO trash (trashed, {columns})
+                                                  SELECT ?, {columns} FROM pastes WHERE id = ?", columns = PASTE_COLUMNS),
+                                        &[&now, &id]).map_err(sql_error));
+        if trashed == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("paste {} does not exist", id)));
+        }
+        try!(conn.execute("DELETE FROM pastes WHERE id = ?", &[&id]).map_err(sql_error));
+        // any left over from the last time a paste with this ID was trashed
+        try!(conn.execute("DELETE FROM versions WHERE id = ?", &[&trash_id]).map_err(sql_error));
+        try!(conn.execute("UPDATE versions SET id = ? WHERE id = ?", &[&trash_id, &id]).map_err(sql_error));
+        Ok(())
+    }
+
+    fn trashed(&self, id: &str) -> Option<(i64, Option<PasteMeta>)> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn.query_row(&format!("SELECT trashed, expires IS NOT NULL, {} FROM trash WHERE id = ?", META_COLUMNS),
+                                 &[&id], |row| {
+            let meta = if row.get(1) { Some(self.row_meta(&row, 2)) } else { None };
+            (row.get(0), meta)
+        });
+        row.ok()
+    }
+
+    fn restore(&self, id: &str) -> io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = try!(conn.query_row("SELECT COUNT(*) FROM pastes WHERE id = ?",
+                                             &[&id], |row| row.get(0)).map_err(sql_error));
+        if count > 0 {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("paste {} already exists", id)));
+        }
+        let restored = try!(conn.execute(&format!("INSERT INTO pastes ({columns}) SELECT {columns} FROM trash WHERE id = ?",
+                                                  columns = PASTE_COLUMNS),
+                                         &[&id]).map_err(sql_error));
+        if restored == 0 {
+            return Ok(false);
+        }
+        try!(conn.execute("DELETE FROM trash WHERE id = ?", &[&id]).map_err(sql_error));
+        try!(conn.execute("UPDATE versions SET id = ? WHERE id = ?", &[&id, &format!("{}.trash", id)]).map_err(sql_error));
+        Ok(true)
+    }
+
+    fn purge_trash(&self, cutoff: i64) -> io::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut purged = Vec::new();
+        {
+            let mut stmt = try!(conn.prepare("SELECT id FROM trash WHERE trashed <= ?").map_err(sql_error));
+            let rows = try!(stmt.query_map(&[&cutoff], |row| row.get(0)).map_err(sql_error));
+            for id in rows {
+                purged.push(try!(id.map_err(sql_error)));
+            }
+        }
+        try!(conn.execute("DELETE FROM versions WHERE id IN (SELECT id || '.trash' FROM trash WHERE trashed <= ?)",
+                          &[&cutoff]).map_err(sql_error));
+        try!(conn.execute("DELETE FROM trash WHERE trashed <= ?", &[&cutoff]).map_err(sql_error));
+        Ok(purged)
+    }
+
+    fn load_meta(&self, id: &str) -> Option<PasteMeta> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn.query_row(&format!("SELECT {} FROM pastes WHERE id = ? AND expires IS NOT NULL", META_COLUMNS),
+                                 &[&id], |row| self.row_meta(&row, 0));
+        row.ok()
+    }
+
+    fn save_meta(&self, id: &str, meta: &PasteMeta) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let views = meta.views as i64;
+        let refs = meta.refs as i64;
+        let renewals = meta.renewals as i64;
+        let tags = if meta.tags.is_empty() { None } else { Some(meta.tags.join(",")) };
+        let ref_keys = if meta.ref_keys.is_empty() { None } else { Some(meta.ref_keys.join(",")) };
+        let updated = try!(conn.execute("UPDATE pastes SET expires = ?, ttl = ?, burn = ?, password = ?, filename = ?, views = ?, title = ?,
+                                         hash = ?, refs = ?, key_hash = ?, owner = ?, renewals = ?, language = ?,
+                                         submitter = ?, tags = ?, ref_keys = ? WHERE id = ?",
+                                        &[&meta.expires, &meta.ttl, &meta.burn, &meta.password, &meta.filename, &views,
+                                          &meta.title, &meta.hash, &refs, &meta.key_hash, &meta.owner, &renewals,
+                                          &meta.language, &meta.submitter, &tags, &ref_keys, &id])
+                               .map_err(sql_error));
+        if updated == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("paste {} does not exist", id)));
+        }
+        Ok(())
+    }
+
+    fn save_version(&self, id: &str, paste: &[u8], keep: usize) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let now = UTC::now().timestamp();
+        try!(conn.execute("INSERT INTO versions (id, version, content, saved)
+                           SELECT ?, COALESCE(MAX(version), 0) + 1, ?, ? FROM versions WHERE id = ?",
+                          &[&id, &paste, &now, &id]).map_err(sql_error));
+        let keep = keep as i64;
+        try!(conn.execute("DELETE FROM versions WHERE id = ? AND version NOT IN
+                               (SELECT version FROM versions WHERE id = ? ORDER BY version DESC LIMIT ?)",
+                          &[&id, &id, &keep]).map_err(sql_error));
+        Ok(())
+    }
+
+    fn list_versions(&self, id: &str) -> io::Result<Vec<(u64, i64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT version, saved FROM versions WHERE id = ? ORDER BY version")
+                                .map_err(sql_error));
+        let rows = try!(stmt.query_map(&[&id], |row| (row.get::<i32, i64>(0) as u64, row.get(1))).map_err(sql_error));
+        let mut versions = Vec::new();
+        for version in rows {
+            versions.push(try!(version.map_err(sql_error)));
+        }
+        Ok(versions)
+    }
+
+    fn load_version(&self, id: &str, version: u64) -> io::Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock().unwrap();
+        let version = version as i64;
+        optional(conn.query_row("SELECT content FROM versions WHERE id = ? AND version = ?",
+                                &[&id, &version], |row| row.get(0)))
+    }
+
+    fn list_owned(&self, owner: &str) -> io::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT id FROM pastes WHERE owner = ? ORDER BY id").map_err(sql_error));
+        let rows = try!(stmt.query_map(&[&owner], |row| row.get(0)).map_err(sql_error));
+        let mut owned = Vec::new();
+        for id in rows {
+            owned.push(try!(id.map_err(sql_error)));
+        }
+        Ok(owned)
+    }
+
+    // Tags are kept comma-separated, so each is looked for with commas around it.
+    fn list_tagged(&self, tag: &str) -> io::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT id FROM pastes WHERE instr(',' || tags || ',', ?) > 0 ORDER BY id")
+                                .map_err(sql_error));
+        let rows = try!(stmt.query_map(&[&format!(",{},", tag)], |row| row.get(0)).map_err(sql_error));
+        let mut tagged = Vec::new();
+        for id in rows {
+            tagged.push(try!(id.map_err(sql_error)));
+        }
+        Ok(tagged)
+    }
+
+    fn count_submitted(&self, submitter: &str, now: i64) -> io::Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT COUNT(*) FROM pastes WHERE submitter = ? AND expires > ?",
+                       &[&submitter, &now], |row| row.get::<i32, i64>(0) as u64).map_err(sql_error)
+    }
+
+    fn list_viewable(&self, now: i64) -> io::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT id FROM pastes WHERE expires > ? AND NOT COALESCE(burn, 0)
+                                          AND password IS NULL")
+                                .map_err(sql_error));
+        let rows = try!(stmt.query_map(&[&now], |row| row.get(0)).map_err(sql_error));
+        let mut viewable = Vec::new();
+        for id in rows {
+            viewable.push(try!(id.map_err(sql_error)));
+        }
+        Ok(viewable)
+    }
+
+    fn find_by_hash(&self, hash: &str) -> io::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        optional(conn.query_row("SELECT id FROM pastes WHERE hash = ? LIMIT 1", &[&hash], |row| row.get(0)))
+    }
+
+    fn modified(&self, id: &str) -> io::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        match try!(optional(conn.query_row("SELECT modified FROM pastes WHERE id = ?", &[&id], |row| row.get(0)))) {
+            Some(modified) => Ok(modified),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("paste {} does not exist", id)))
+        }
+    }
+
+    fn size(&self, id: &str) -> io::Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        match try!(optional(conn.query_row("SELECT LENGTH(content) FROM pastes WHERE id = ?",
+                                           &[&id], |row| row.get::<i32, i64>(0)))) {
+            Some(size) => Ok(size as u64),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("paste {} does not exist", id)))
+        }
+    }
+
+    fn usage(&self) -> io::Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let total: i64 = try!(conn.query_row("SELECT (SELECT COALESCE(SUM(LENGTH(content)), 0) FROM pastes)
+                                                    + (SELECT COALESCE(SUM(LENGTH(content)), 0) FROM versions)
+                                                    + (SELECT COALESCE(SUM(LENGTH(content)), 0) FROM trash)",
+                                             &[], |row| row.get(0)).map_err(sql_error));
+        Ok(total as u64)
+    }
+
+    fn count(&self) -> io::Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = try!(conn.query_row("SELECT COUNT(*) FROM pastes", &[], |row| row.get(0)).map_err(sql_error));
+        Ok(count as u64)
+    }
+
+    fn list_page(&self, offset: usize, limit: usize) -> io::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare("SELECT id FROM pastes ORDER BY id LIMIT ? OFFSET ?").map_err(sql_error));
+        let rows = try!(stmt.query_map(&[&(limit as i64), &(offset as i64)], |row| row.get(0)).map_err(sql_error));
+        let mut ids = Vec::new();
+        for id in rows {
+            ids.push(try!(id.map_err(sql_error)));
+        }
+        Ok(ids)
+    }
+
+    fn stats(&self) -> io::Result<Stats> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT COUNT(*), COALESCE(SUM(LENGTH(content)), 0), MIN(modified), MAX(modified) FROM pastes",
+                       &[], |row| Stats {
+                           pastes: row.get::<i32, i64>(0) as u64,
+                           bytes: row.get::<i32, i64>(1) as u64,
+                           oldest: row.get(2),
+                           newest: row.get(3)
+                       }).map_err(sql_error)
+    }
+
+    fn check(&self) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT 1", &[], |_| ()).map_err(sql_error)
+    }
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, kind: &str) -> io::Result<()> {
+    let mut stmt = try!(conn.prepare(&format!("PRAGMA table_info({})", table)).map_err(sql_error));
+    let names = try!(stmt.query_map(&[], |row| row.get::<i32, String>(1)).map_err(sql_error));
+    for name in names {
+        if try!(name.map_err(sql_error)) == column {
+            return Ok(());
+        }
+    }
+    try!(conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, kind), &[]).map_err(sql_error));
+    Ok(())
+}
+
+// Tags are stored as they are listed, comma-separated.
+// Tags and key hashes are stored comma-separated, neither containing commas.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',').filter(|item| !item.is_empty()).map(|item| item.to_string()).collect()
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+// A missing row is not an error as far as callers are concerned.
+fn optional<T>(result: rusqlite::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(sql_error(e))
+    }
+}
+
+// Files are written out in full next to where they belong, and only then moved
+// into place, so that readers (and crashes) see either the old contents or the
+// new, never half of a write. Returns the temporary path.
+fn write_temp<F>(path: &str, write: F) -> io::Result<String>
+    where F: FnOnce(&mut File) -> io::Result<()>
+{
+    let temp = format!("{path}.tmp{tag:x}", path = path, tag = rand::random::<u64>());
+    let result = File::create(&temp).and_then(|mut f| {
+        try!(write(&mut f));
+        f.sync_all()
+    });
+    match result {
+        Ok(()) => Ok(temp),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+// Renaming within a directory is atomic.
+fn rename_or_remove(temp: &str, path: &str) -> io::Result<()> {
+    let result = fs::rename(temp, path);
+    if result.is_err() {
+        let _ = fs::remove_file(temp);
+    }
+    result
+}
+
+// Files which disappear while they are being measured are left out.
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue
+        };
+        total += match entry.file_type() {
+            Ok(ref kind) if kind.is_dir() => dir_size(&entry.path()).unwrap_or(0),
+            Ok(_) => entry.metadata().map(|attr| attr.len()).unwrap_or(0),
+            Err(_) => 0
+        };
+    }
+    Ok(total)
+}
+
+fn remove_if_exists(path: &str) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result
+    }
+}
+
+// Pastes are stored gzipped.
+fn write_paste(f: &mut File, paste: &[u8]) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+    try!(encoder.write_all(paste));
+    let compressed = try!(encoder.finish());
+    f.write_all(&compressed)
+}
+
+// Pastes written before compression was introduced are read as they are.
+fn read_paste(f: &mut File) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    try!(f.read_to_end(&mut bytes));
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        return Ok(bytes);
+    }
+    let mut paste = Vec::new();
+    let mut decoder = try!(GzDecoder::new(&bytes[..]));
+    try!(decoder.read_to_end(&mut paste));
+    Ok(paste)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    // A FilesystemStorage in a directory of its own, removed afterwards.
+    struct TempStorage {
+        dir: String,
+        storage: FilesystemStorage
+    }
+
+    impl TempStorage {
+        fn new(default_ttl: i64) -> TempStorage {
+            let dir = env::temp_dir().join(format!("pastebin-test-{}", rand::random::<u64>()));
+            let dir = dir.to_string_lossy().into_owned();
+            let storage = FilesystemStorage::new(&format!("{}/pastes", dir), &format!("{}/meta", dir), default_ttl)
+                .unwrap();
+            TempStorage { dir: dir, storage: storage }
+        }
+    }
+
+    impl Drop for TempStorage {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Starts like gzip, and isn't UTF-8.
+    const GZIP_LOOKALIKE: &'static [u8] = &[0x1f, 0x8b, 0x08, 0xff, 0xfe, 0x00, b'x', 0xc3];
+
+    #[test]
+    fn pastes_which_look_gzipped_round_trip() {
+        let path = env::temp_dir().join(format!("pastebin-test-{}", rand::random::<u64>()));
+        let mut f = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let result = write_paste(&mut f, GZIP_LOOKALIKE)
+            .and_then(|_| f.seek(SeekFrom::Start(0)))
+            .and_then(|_| read_paste(&mut f));
+        let _ = fs::remove_file(&path);
+        assert_eq!(result.unwrap(), GZIP_LOOKALIKE);
+
+        let temp = TempStorage::new(60);
+        temp.storage.create("abcde", GZIP_LOOKALIKE).unwrap();
+        assert_eq!(temp.storage.load("abcde").unwrap().unwrap(), GZIP_LOOKALIKE);
+    }
+
+    #[test]
+    fn take_removes_everything_but_keeps_unreadable_pastes() {
+        let temp = TempStorage::new(60);
+        let storage = &temp.storage;
+        storage.create("abcde", b"paste").unwrap();
+        let mut meta = PasteMeta::new(60);
+        meta.hash = Some("0123".to_string());
+        storage.save_meta("abcde", &meta).unwrap();
+        storage.save_version("abcde", b"older", 5).unwrap();
+        assert_eq!(storage.find_by_hash("0123").unwrap(), Some("abcde".to_string()));
+
+        assert_eq!(storage.take("abcde").unwrap(), Some(b"paste".to_vec()));
+        assert!(!storage.exists("abcde"));
+        assert!(storage.load_meta("abcde").is_none());
+        assert!(storage.list_versions("abcde").unwrap().is_empty());
+        assert!(!Path::new(&storage.hash_path("0123")).exists());
+        assert_eq!(storage.take("abcde").unwrap(), None);
+
+        // looks gzipped, but isn't
+        File::create(storage.paste_path("fghij")).unwrap().write_all(&[0x1f, 0x8b, 0x00, 0x01]).unwrap();
+        assert!(storage.take("fghij").is_err());
+        assert!(storage.exists("fghij"));
+        let names: Vec<String> = fs::read_dir(&storage.upload_dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["fghij".to_string()]);
+    }
+
+    #[test]
+    fn delete_expired_skips_bad_metadata() {
+        let temp = TempStorage::new(0);
+        let storage = &temp.storage;
+        let now = UTC::now().timestamp();
+        for id in &["expired", "garbage", "unreadable", "live"] {
+            storage.create(id, b"paste").unwrap();
+        }
+        let mut meta = PasteMeta::new(60);
+        meta.expires = now - 60;
+        storage.save_meta("expired", &meta).unwrap();
+        meta.expires = now + 60;
+        storage.save_meta("live", &meta).unwrap();
+        File::create(storage.meta_path("garbage")).unwrap().write_all(b"\xff\xfe=\n\x00expires=soon").unwrap();
+        fs::create_dir(storage.meta_path("unreadable")).unwrap();
+
+        let results = storage.delete_expired(now).unwrap();
+        let deleted: Vec<&str> = results.iter()
+            .filter(|&&(_, ref result)| result.is_ok())
+            .map(|&(ref id, _)| id.as_str())
+            .collect();
+        assert!(deleted.contains(&"expired"), "{:?}", results);
+        assert!(!storage.exists("expired"));
+        assert_eq!(storage.tombstone("expired"), Some("expired".to_string()));
+        assert!(storage.exists("live"));
+    }
+}